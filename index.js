@@ -1,4 +1,4 @@
-const { startup } = wasm_bindgen;
+const { startup, default_config } = wasm_bindgen;
 
 let worker;
 
@@ -19,7 +19,7 @@ canvas.height = canvasHeight;
 
 const title = "Pong";
 
-const QUADRANTS = 200;
+let QUADRANTS = 200;
 
 let widthStep = () => canvas.width / QUADRANTS;
 let heightStep = () => canvas.height / QUADRANTS;
@@ -170,6 +170,23 @@ let PlayerEnum = {
   TWO: "TWO",
 };
 
+function applyConfig(config) {
+  QUADRANTS = config.game.quadrants;
+  paddleConfig = {
+    width: config.game.paddle_width,
+    height: config.game.paddle_height,
+    speed: config.game.paddle_speed,
+  };
+  ballConfig = {
+    size: config.game.ball_size,
+    dx: config.game.ball_dx,
+    dy: config.game.ball_dy,
+  };
+  p1.reset();
+  p2.reset();
+  ball.reset();
+}
+
 function draw() {
   context.clearRect(0, 0, canvas.width, canvas.height);
   p1.draw();
@@ -389,11 +406,28 @@ function getGameBoard() {
   return gameBoard;
 }
 
+// The ball's x, y, dx and dy, then the top of each paddle, in board cells like
+// getGameBoard. The worker hands them to handle_img for the features observation.
+function getPositions() {
+  return [
+    ball.x / widthStep(),
+    ball.y / heightStep(),
+    ball.dx / widthStep(),
+    ball.dy / heightStep(),
+    p1.y / heightStep(),
+    p2.y / heightStep(),
+  ];
+}
+
 function gameLoop() {
   update();
   draw();
   if (worker) {
-    worker.postMessage({ type: "state", data: getGameBoard() });
+    worker.postMessage({
+      type: "state",
+      data: getGameBoard(),
+      positions: getPositions(),
+    });
   }
   setTimeout(gameLoop, 50);
 }
@@ -403,11 +437,22 @@ async function run_wasm() {
   console.log("index.js loaded");
   startup();
 
+  let config = JSON.parse(get_data("config")) || default_config();
+  set_data("config", JSON.stringify(config));
+  applyConfig(config);
+
   worker = new Worker("worker.js");
+  worker.postMessage({ type: "config", data: config });
   worker.onmessage = function (e) {
     if (e.data.type == "setDataMain") {
       set_data(e.data.key, e.data.value);
     }
+    if (e.data.type == "benchmark") {
+      console.log(
+        `${e.data.data.iterations} moves in wasm: MLP ${e.data.data.mlp_ms.toFixed(3)} ms, ` +
+          `CNN ${e.data.data.cnn_ms.toFixed(3)} ms per move`,
+      );
+    }
     if (e.data.type == "getDataMain") {
       worker.postMessage({ type: "getDataWorker", data: get_data(e.data.key) });
     }
@@ -435,6 +480,14 @@ async function run_wasm() {
   };
 }
 
+// Times a move with the MLP and the CNN in the worker's wasm, from the console
+// with e.g. benchmarkInference(1000). The result is logged when it's back.
+window.benchmarkInference = function (iterations = 1000) {
+  if (worker) {
+    worker.postMessage({ type: "benchmark", data: iterations });
+  }
+};
+
 run_wasm();
 
 gameLoop();
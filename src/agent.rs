@@ -0,0 +1,145 @@
+use crate::{
+    config::{AgentKind, Config, TrainConfig},
+    dqn::{Dqn, ReplayBuffer},
+    model::{Inference, Model, ModelError, TrainStats},
+    observation::Frame,
+    state::Sequence,
+};
+
+use rand::{Rng, RngCore};
+use wasm_bindgen::prelude::*;
+use web_sys::js_sys::Object;
+
+/// What the browser and the `train` binary need from something that plays
+/// from the frames it sees and learns from finished games
+pub trait Agent {
+    fn id(&self) -> u8;
+
+    fn config(&self) -> &Config;
+
+    /// Swaps the training hyperparameters, which don't affect the weights' shape
+    fn set_train_config(&mut self, train: TrainConfig);
+
+    /// How many recent frames `infer` wants to see
+    fn history(&self) -> usize;
+
+    /// Picks a move from the frames a player has seen this game, oldest first
+    /// and ending with the current one
+    fn infer(&self, frames: &[Frame], rng: &mut dyn RngCore) -> Inference;
+
+    /// Learns from a batch of finished games
+    fn train(&mut self, batch: &[Sequence]) -> Option<TrainStats>;
+
+    /// The agent as its record in the model store
+    fn to_jsobject(&self) -> Result<Object, JsValue>;
+
+    fn to_json(&self) -> Result<String, ModelError>;
+
+    fn to_safetensors(&self) -> Result<Vec<u8>, ModelError>;
+
+    /// A copy with its own weights, e.g. for an opponent
+    fn boxed_clone(&self) -> Box<dyn Agent>;
+
+    /// The transitions the agent trains on, stored apart from it
+    fn replay_buffer(&self) -> Option<&ReplayBuffer> {
+        None
+    }
+}
+
+/// A new agent of the kind `config` asks for
+pub fn new_agent<R: Rng + ?Sized>(config: Config, rng: &mut R) -> Box<dyn Agent> {
+    match config.agent {
+        AgentKind::PolicyGradient => Box::new(Model::new(config, rng)),
+        AgentKind::Dqn => Box::new(Dqn::new(config, rng)),
+    }
+}
+
+impl Agent for Model {
+    fn id(&self) -> u8 {
+        Model::id(self)
+    }
+
+    fn config(&self) -> &Config {
+        Model::config(self)
+    }
+
+    fn set_train_config(&mut self, train: TrainConfig) {
+        Model::set_train_config(self, train)
+    }
+
+    fn history(&self) -> usize {
+        Model::history(self)
+    }
+
+    fn infer(&self, frames: &[Frame], rng: &mut dyn RngCore) -> Inference {
+        Model::infer(self, frames, rng)
+    }
+
+    fn train(&mut self, batch: &[Sequence]) -> Option<TrainStats> {
+        Model::train(self, batch)
+    }
+
+    fn to_jsobject(&self) -> Result<Object, JsValue> {
+        Model::to_jsobject(self)
+    }
+
+    fn to_json(&self) -> Result<String, ModelError> {
+        Ok(serde_json::to_string(&self.serialize()?)?)
+    }
+
+    fn to_safetensors(&self) -> Result<Vec<u8>, ModelError> {
+        Model::to_safetensors(self)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Agent> {
+        Box::new(self.clone())
+    }
+}
+
+impl Agent for Dqn {
+    fn id(&self) -> u8 {
+        Dqn::id(self)
+    }
+
+    fn config(&self) -> &Config {
+        Dqn::config(self)
+    }
+
+    fn set_train_config(&mut self, train: TrainConfig) {
+        Dqn::set_train_config(self, train)
+    }
+
+    fn history(&self) -> usize {
+        Dqn::history(self)
+    }
+
+    fn infer(&self, frames: &[Frame], rng: &mut dyn RngCore) -> Inference {
+        Dqn::infer(self, frames, rng)
+    }
+
+    fn train(&mut self, batch: &[Sequence]) -> Option<TrainStats> {
+        Dqn::train(self, batch)
+    }
+
+    fn to_jsobject(&self) -> Result<Object, JsValue> {
+        Dqn::to_jsobject(self)
+    }
+
+    fn to_json(&self) -> Result<String, ModelError> {
+        Ok(serde_json::to_string(&self.serialize()?)?)
+    }
+
+    fn to_safetensors(&self) -> Result<Vec<u8>, ModelError> {
+        Err(ModelError::Unsupported(
+            "a DQN agent is only written as JSON".to_string(),
+        ))
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Agent> {
+        Box::new(self.clone())
+    }
+
+    fn replay_buffer(&self) -> Option<&ReplayBuffer> {
+        Some(self.buffer())
+    }
+}
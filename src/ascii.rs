@@ -0,0 +1,87 @@
+use crate::{
+    engine::Action,
+    raster::Board,
+    state::{Distribution, Image, Sequence, State},
+};
+
+/// Width of the probability bars
+const BAR_WIDTH: usize = 20;
+
+/// Draws a `w` x `h` grid with a border, x across and y down like the canvas
+fn grid(w: usize, h: usize, cell: impl Fn(usize, usize) -> bool) -> String {
+    let border = format!("+{}+\n", "-".repeat(w));
+    let mut out = border.clone();
+    for y in 0..h {
+        out.push('|');
+        out.extend((0..w).map(|x| if cell(x, y) { 'X' } else { ' ' }));
+        out.push_str("|\n");
+    }
+    out.push_str(&border);
+    out
+}
+
+/// Draws a downsampled frame. Images are stored column by column, like the
+/// `[x][y]` board they were pooled from.
+pub fn render_image(img: &Image) -> String {
+    let dim = (img.len() as f64).sqrt() as usize;
+    grid(dim, dim, |x, y| img[x * dim + y] != 0)
+}
+
+pub fn render_board(board: &Board) -> String {
+    let w = board.len();
+    let h = board.first().map_or(0, |column| column.len());
+    grid(w, h, |x, y| board[x][y])
+}
+
+/// One bar per choice, labelled with the move `index.js` makes for it, and an
+/// arrow next to the one that was picked
+pub fn render_distribution(dist: &Distribution, choice: u8) -> String {
+    dist.to_vec()
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let filled = ((p.clamp(0.0, 1.0) * BAR_WIDTH as f32).round()) as usize;
+            format!(
+                "{} {:<5} {:.3} [{}{}]{}\n",
+                i,
+                format!("{:?}", Action::from(i as u8)).to_lowercase(),
+                p,
+                "#".repeat(filled),
+                " ".repeat(BAR_WIDTH - filled),
+                if i as u8 == choice { " <" } else { "" }
+            )
+        })
+        .collect()
+}
+
+pub fn render_frame(img: &Image, dist: &Distribution, choice: u8) -> String {
+    render_image(img) + &render_distribution(dist, choice)
+}
+
+pub fn render_state(state: &State) -> String {
+    let (img, inference) = state.to_tuple();
+    render_frame(&img, &inference.dist, inference.choice)
+}
+
+/// Every frame of a game in order, with a title line, for stepping through it
+pub fn render_sequence(seq: &Sequence) -> Vec<String> {
+    let outcome = match seq.get_outcome() {
+        Some(true) => "won",
+        Some(false) => "lost",
+        None => "in progress",
+    };
+    seq.get_sequence()
+        .iter()
+        .enumerate()
+        .map(|(i, state)| {
+            format!(
+                "game {} ({}) frame {}/{}\n{}",
+                seq.get_id(),
+                outcome,
+                i + 1,
+                seq.len(),
+                render_state(state)
+            )
+        })
+        .collect()
+}
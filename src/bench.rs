@@ -0,0 +1,88 @@
+use crate::{
+    config::{Config, ConvLayer},
+    model::{Activation, Model},
+    observation::{Features, Frame, ObservationMode},
+    rng,
+    state::now,
+};
+
+use rand::Rng;
+use serde::Serialize;
+
+/// Conv layers to compare with the MLP when the config has none: two 3x3
+/// layers, the second halving the board
+pub fn default_conv() -> Vec<ConvLayer> {
+    vec![
+        ConvLayer {
+            channels: 8,
+            kernel: 3,
+            stride: 1,
+            padding: 1,
+            activation: Activation::Relu,
+        },
+        ConvLayer {
+            channels: 16,
+            kernel: 3,
+            stride: 2,
+            padding: 1,
+            activation: Activation::Relu,
+        },
+    ]
+}
+
+/// Milliseconds per `Model::infer` of a fresh model built with `config`,
+/// averaged over `iterations` moves on a random board
+pub fn inference_latency(config: &Config, iterations: usize) -> f64 {
+    let mut rng = rng::seeded(0);
+    let model = Model::new(config.clone(), &mut rng);
+    let frames = (0..model.history())
+        .map(|_| {
+            let img = (0..config.frame_size())
+                .map(|_| rng.gen_range(0..2))
+                .collect();
+            let features = Features {
+                ball_x: rng.gen(),
+                ball_y: rng.gen(),
+                ball_dx: rng.gen(),
+                ball_dy: rng.gen(),
+                paddle_y: rng.gen(),
+                opponent_y: rng.gen(),
+            };
+            Frame::new(img, Some(features))
+        })
+        .collect::<Vec<_>>();
+    let start = now();
+    for _ in 0..iterations {
+        model.infer(&frames, &mut rng);
+    }
+    (now() - start) / iterations.max(1) as f64
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct InferenceBenchmark {
+    pub iterations: usize,
+    /// Milliseconds per move without conv layers
+    pub mlp_ms: f64,
+    /// Milliseconds per move with them
+    pub cnn_ms: f64,
+}
+
+/// Times `config` as an MLP and as a CNN, with its own conv layers or
+/// `default_conv` if it has none. Everything else about the network is kept,
+/// except that the CNN sees the board rather than features.
+pub fn compare(config: &Config, iterations: usize) -> InferenceBenchmark {
+    let mut mlp = config.clone();
+    mlp.model.conv.clear();
+    let mut cnn = config.clone();
+    if cnn.model.conv.is_empty() {
+        cnn.model.conv = default_conv();
+    }
+    if !cnn.model.observation.is_image() {
+        cnn.model.observation = ObservationMode::Frame;
+    }
+    InferenceBenchmark {
+        iterations,
+        mlp_ms: inference_latency(&mlp, iterations),
+        cnn_ms: inference_latency(&cnn, iterations),
+    }
+}
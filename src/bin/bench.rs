@@ -0,0 +1,51 @@
+//! Compares how long a move takes with the MLP and with the CNN policy.
+//!
+//! `--config` takes a JSON `Config`. Its conv layers are used for the CNN, or
+//! `bench::default_conv` if it has none. The browser runs the same comparison
+//! with `benchmark_inference`.
+//!
+//! cargo run --release --bin bench -- --iterations 1000
+
+use pong_wasm::{bench::compare, config::Config};
+
+use std::{env, fs, process};
+
+fn usage() -> ! {
+    eprintln!("usage: bench [--iterations N] [--config PATH]");
+    process::exit(2);
+}
+
+fn main() {
+    let mut iterations = 1000;
+    let mut config = Config::default();
+    let mut argv = env::args().skip(1);
+    while let Some(flag) = argv.next() {
+        let value = argv.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--iterations" => iterations = value.parse().unwrap_or_else(|_| usage()),
+            "--config" => {
+                config = fs::read_to_string(&value)
+                    .map_err(|e| e.to_string())
+                    .and_then(|c| serde_json::from_str(&c).map_err(|e| e.to_string()))
+                    .unwrap_or_else(|e| {
+                        eprintln!("failed to load {}: {}", value, e);
+                        process::exit(1);
+                    })
+            }
+            _ => usage(),
+        }
+    }
+    config.validate().unwrap_or_else(|e| {
+        eprintln!("invalid config: {}", e);
+        process::exit(1);
+    });
+
+    let result = compare(&config, iterations);
+    println!(
+        "{} moves: MLP {:.3} ms, CNN {:.3} ms per move ({:.1}x)",
+        result.iterations,
+        result.mlp_ms,
+        result.cnn_ms,
+        result.cnn_ms / result.mlp_ms
+    );
+}
@@ -0,0 +1,54 @@
+//! Steps through a replay file in the terminal.
+//!
+//! Enter shows the next frame, `b` goes back one and `q` quits.
+//!
+//! cargo run --bin replay -- replays/episode-100.replay
+
+use pong_wasm::{ascii::render_frame, replay::Replay};
+
+use std::{
+    env, fs,
+    io::{self, BufRead},
+    process,
+};
+
+fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| {
+        eprintln!("usage: replay FILE");
+        process::exit(2);
+    });
+    let replay = fs::read(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| Replay::from_bytes(&bytes).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("failed to load {}: {}", path, e);
+            process::exit(1);
+        });
+
+    let header = &replay.header;
+    println!(
+        "model {} seed {:?}, player one {}, {} frames",
+        header.model_id,
+        header.seed,
+        match header.outcome {
+            Some(true) => "won",
+            Some(false) => "lost",
+            None => "didn't finish",
+        },
+        replay.frames.len()
+    );
+
+    let mut lines = io::stdin().lock().lines();
+    let mut i = 0;
+    while i < replay.frames.len() {
+        let frame = &replay.frames[i];
+        println!("frame {}/{}", i + 1, replay.frames.len());
+        print!("{}", render_frame(&frame.img, &frame.dist, frame.choice));
+        match lines.next() {
+            Some(Ok(line)) if line.trim() == "q" => break,
+            Some(Ok(line)) if line.trim() == "b" => i = i.saturating_sub(1),
+            Some(Ok(_)) => i += 1,
+            _ => break,
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Headless self-play training.
+//!
+//! Plays `--episodes` points with the same agent on both paddles, trains on
+//! player one's frames every `batch_size` points like the browser's "train"
+//! mode, and writes the agent to `--out`, as safetensors if the path ends in
+//! `.safetensors` and JSON otherwise. `--config` takes a JSON `Config` like
+//! `get_config` returns, `--load` starts from a safetensors model and
+//! `--replays DIR` saves the last point of every report.
+//!
+//! cargo run --release --bin train -- --episodes 5000 --seed 0 --observation difference --out model.json
+
+use pong_wasm::{
+    agent::{new_agent, Agent},
+    config::{AgentKind, Config},
+    env::{action_entropy, model_opponent, rollout, Pong, ENTROPY_FRAMES},
+    model::{Model, TrainStats},
+    observation::{Frame, ObservationMode},
+    replay::Replay,
+    rng,
+    state::{Sequence, State},
+};
+
+use rand::Rng;
+use std::{env, fs, path::Path, process};
+
+/// A point that runs longer than this is thrown away rather than trained on
+const MAX_FRAMES: usize = 10_000;
+const REPORT_EVERY: usize = 100;
+
+struct Args {
+    episodes: usize,
+    seed: u64,
+    config: Config,
+    observation: Option<ObservationMode>,
+    replays: Option<String>,
+    load: Option<String>,
+    out: String,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: train [--episodes N] [--seed N] [--config PATH] \
+         [--observation frame|difference|stack:K|features] [--replays DIR] [--load PATH] [--out PATH]"
+    );
+    process::exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        episodes: 1000,
+        seed: 0,
+        config: Config::default(),
+        observation: None,
+        replays: None,
+        load: None,
+        out: "model.json".to_string(),
+    };
+    let mut argv = env::args().skip(1);
+    while let Some(flag) = argv.next() {
+        let value = argv.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--episodes" => args.episodes = value.parse().unwrap_or_else(|_| usage()),
+            "--seed" => args.seed = value.parse().unwrap_or_else(|_| usage()),
+            "--config" => args.config = load_config(&value),
+            "--observation" => args.observation = Some(value.parse().unwrap_or_else(|_| usage())),
+            "--replays" => args.replays = Some(value),
+            "--load" => args.load = Some(value),
+            "--out" => args.out = value,
+            _ => usage(),
+        }
+    }
+    if let Some(observation) = args.observation {
+        args.config.model.observation = observation;
+    }
+    args.config.validate().unwrap_or_else(|e| {
+        eprintln!("invalid config: {}", e);
+        process::exit(1);
+    });
+    args
+}
+
+fn load_config(path: &str) -> Config {
+    let config = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {}", path, e);
+        process::exit(1);
+    });
+    serde_json::from_str(&config).unwrap_or_else(|e| {
+        eprintln!("failed to parse {}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+/// Loads a policy network, refusing flags that would change what it is
+fn load_model(path: &str, args: &Args) -> Box<dyn Agent> {
+    let mut model = Model::load_safetensors(path).unwrap_or_else(|e| {
+        eprintln!("failed to load {}: {}", path, e);
+        process::exit(1);
+    });
+    let loaded = model.config().model.observation;
+    let conflict = match args.observation {
+        Some(observation) if observation != loaded => Some(format!(
+            "--observation {:?} conflicts with {}, which observes {:?}",
+            observation, path, loaded
+        )),
+        _ if args.config.agent == AgentKind::Dqn => Some(format!(
+            "a DQN agent conflicts with {}, which is a policy network",
+            path
+        )),
+        _ => None,
+    };
+    if let Some(conflict) = conflict {
+        eprintln!("{}", conflict);
+        process::exit(2);
+    }
+    model.set_train_config(args.config.train);
+    Box::new(model)
+}
+
+/// Plays a single point and returns player one's side of it, or None if it ran
+/// past MAX_FRAMES
+fn play_point(
+    config: &Config,
+    model: &dyn Agent,
+    pong: &mut Pong,
+    rng: &mut rng::ModelRng,
+    id: f64,
+) -> Option<Sequence> {
+    pong.set_opponent(model_opponent(model.boxed_clone(), rng::seeded(rng.gen())));
+    let mut seq = Sequence::new_with_id(id);
+    let keep = model.history();
+    let policy = |frame: &Frame| {
+        let frames = seq.observe(0, frame.clone(), keep);
+        let inference = model.infer(&frames, rng);
+        let choice = inference.choice;
+        seq.push(State::new(frame.clone(), inference));
+        choice.into()
+    };
+    let point = rollout(pong, policy, MAX_FRAMES)?;
+    seq.end(point.reward > 0.0, config.train.rewards);
+    Some(seq)
+}
+
+/// The mean of the metrics only some training modes report, over every update
+/// since the last report, or nothing if there were none
+fn train_metrics(stats: &[TrainStats]) -> String {
+    let metric = |metric: fn(&TrainStats) -> Option<f32>| -> Vec<f32> {
+        stats.iter().filter_map(metric).collect()
+    };
+    vec![
+        ("value loss", metric(|s| s.value_loss)),
+        ("clip fraction", metric(|s| s.clip_fraction)),
+        ("KL", metric(|s| s.kl)),
+        ("Q loss", metric(|s| s.q_loss)),
+        ("policy entropy", metric(|s| s.entropy)),
+    ]
+    .into_iter()
+    .filter(|(_, values)| !values.is_empty())
+    .map(|(name, values)| {
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        format!(", {} {:.4}", name, mean)
+    })
+    .collect()
+}
+
+fn save_replay(dir: &str, seq: &Sequence, model: &dyn Agent, seed: u64) {
+    let path = Path::new(dir).join(format!("episode-{}.replay", seq.get_id()));
+    let replay = Replay::from_sequence(seq, model.config().clone(), model.id(), Some(seed));
+    let written = fs::create_dir_all(dir)
+        .map_err(|e| e.to_string())
+        .and_then(|_| replay.to_bytes().map_err(|e| e.to_string()))
+        .and_then(|bytes| fs::write(&path, bytes).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        eprintln!("failed to write {}: {}", path.display(), e);
+    }
+}
+
+fn main() {
+    let mut args = parse_args();
+    let mut rng = rng::seeded(args.seed);
+    let mut model = match &args.load {
+        Some(path) => load_model(path, &args),
+        None => new_agent(args.config.clone(), &mut rng),
+    };
+    // a loaded model brings its own board and network, only training is ours
+    args.config = model.config().clone();
+    println!(
+        "initial action entropy {:.3} (uniform is {:.3})",
+        action_entropy(model.as_ref(), &mut rng::seeded(args.seed), ENTROPY_FRAMES),
+        3f32.ln()
+    );
+    let mut pong = Pong::new(
+        &args.config,
+        model_opponent(model.boxed_clone(), rng::seeded(args.seed)),
+    );
+
+    let mut batch = Vec::with_capacity(args.config.train.batch_size);
+    let mut wins = 0;
+    let mut frames = 0;
+    let mut entropy = 0.0;
+    let mut stats = Vec::new();
+    for episode in 1..=args.episodes {
+        if let Some(seq) = play_point(
+            &args.config,
+            model.as_ref(),
+            &mut pong,
+            &mut rng,
+            episode as f64,
+        ) {
+            wins += seq.get_outcome().unwrap_or(false) as usize;
+            frames += seq.len();
+            entropy += seq.mean_entropy();
+            if let (Some(dir), 0) = (&args.replays, episode % REPORT_EVERY) {
+                save_replay(dir, &seq, model.as_ref(), args.seed);
+            }
+            batch.push(seq);
+            if batch.len() >= args.config.train.batch_size {
+                stats.extend(model.train(&batch));
+                batch.clear();
+            }
+        }
+        if episode % REPORT_EVERY == 0 {
+            println!(
+                "episode {}: player one won {}/{}, {:.1} frames per point, \
+                 {:.3} action entropy per point{}",
+                episode,
+                wins,
+                REPORT_EVERY,
+                frames as f32 / REPORT_EVERY as f32,
+                entropy / REPORT_EVERY as f32,
+                train_metrics(&stats)
+            );
+            wins = 0;
+            frames = 0;
+            entropy = 0.0;
+            stats.clear();
+        }
+    }
+
+    let written = if args.out.ends_with(".safetensors") {
+        model.to_safetensors().map_err(|e| e.to_string())
+    } else {
+        model
+            .to_json()
+            .map(String::into_bytes)
+            .map_err(|e| e.to_string())
+    }
+    .and_then(|bytes| fs::write(&args.out, bytes).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        eprintln!("failed to write {}: {}", args.out, e);
+        process::exit(1);
+    }
+    println!("wrote {}", args.out);
+}
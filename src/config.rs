@@ -0,0 +1,571 @@
+use crate::{
+    consts::{
+        BALL_DX, BALL_DY, BALL_SIZE, BATCH_SIZE, EPSILON, GAMMA, HIDDEN, LEARNING_RATE, MINIBATCH,
+        PADDLE_HEIGHT, PADDLE_SPEED, PADDLE_WIDTH, QUADRANTS, REPLAY_CAPACITY, RESOLUTION,
+        TARGET_EVERY, VALUE_COEF,
+    },
+    logging,
+    model::{Activation, Init},
+    observation::ObservationMode,
+    optim::{Decay, OptimizerKind},
+};
+
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use wasm_bindgen::prelude::*;
+
+/// Board geometry and physics, what `paddleConfig` and `ballConfig` hold in `index.js`.
+/// Sizes are in board cells.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GameConfig {
+    /// The board is `quadrants` x `quadrants` cells
+    pub quadrants: usize,
+    pub paddle_width: f64,
+    pub paddle_height: f64,
+    pub paddle_speed: f64,
+    pub ball_size: f64,
+    pub ball_dx: f64,
+    /// The serve goes out at half of this, like `Ball.reset`
+    pub ball_dy: f64,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            quadrants: QUADRANTS,
+            paddle_width: PADDLE_WIDTH,
+            paddle_height: PADDLE_HEIGHT,
+            paddle_speed: PADDLE_SPEED,
+            ball_size: BALL_SIZE,
+            ball_dx: BALL_DX,
+            ball_dy: BALL_DY,
+        }
+    }
+}
+
+/// A hidden layer of the policy network
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Layer {
+    pub width: usize,
+    #[serde(default)]
+    pub activation: Activation,
+}
+
+/// A convolutional layer over the board, before the hidden layers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConvLayer {
+    /// Output channels
+    pub channels: usize,
+    /// Side of the square kernel
+    pub kernel: usize,
+    #[serde(default = "default_stride")]
+    pub stride: usize,
+    /// Zeros added along every edge of the input
+    #[serde(default)]
+    pub padding: usize,
+    #[serde(default)]
+    pub activation: Activation,
+}
+
+fn default_stride() -> usize {
+    1
+}
+
+impl ConvLayer {
+    /// Side of the output for an input `dim` cells on a side, 0 if the kernel
+    /// doesn't fit
+    pub fn output_dim(&self, dim: usize) -> usize {
+        (dim + 2 * self.padding)
+            .checked_sub(self.kernel)
+            .map_or(0, |d| d / self.stride.max(1) + 1)
+    }
+}
+
+/// The shape of a new policy network and how it sees the board
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ModelConfig {
+    /// The board is pooled by `resolution` x `resolution` cells before the model sees it
+    pub resolution: usize,
+    /// Convolutional layers that see the board as an image, one channel per
+    /// frame the observation holds, before the hidden layers. Without any the
+    /// network is a plain MLP over the flattened board or the features.
+    #[serde(default)]
+    pub conv: Vec<ConvLayer>,
+    /// Hidden layers from the input side, with the output layer after the last
+    pub hidden: Vec<Layer>,
+    pub observation: ObservationMode,
+    #[serde(default)]
+    pub init: Init,
+    /// Adds a value head on the last hidden layer for advantage actor-critic
+    /// training, the returns scale the policy gradient directly without one
+    #[serde(default)]
+    pub value_head: bool,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            resolution: RESOLUTION,
+            conv: Vec::new(),
+            hidden: vec![Layer {
+                width: HIDDEN,
+                activation: Activation::Relu,
+            }],
+            observation: ObservationMode::default(),
+            init: Init::default(),
+            value_head: false,
+        }
+    }
+}
+
+/// What a game is worth to player one, recorded with every `Sequence`
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RewardScheme {
+    /// Given on the last frame of a point player one won
+    pub win: f32,
+    /// Given on the last frame of a point player one lost
+    pub loss: f32,
+    /// Given on every frame, e.g. a small bonus for keeping the rally going
+    pub per_frame: f32,
+}
+
+impl Default for RewardScheme {
+    fn default() -> Self {
+        RewardScheme {
+            win: 1.0,
+            loss: -1.0,
+            per_frame: 0.0,
+        }
+    }
+}
+
+impl RewardScheme {
+    /// The reward for each of `frames` frames of a game with this outcome
+    pub fn rewards(&self, frames: usize, outcome: bool) -> Vec<f32> {
+        let mut rewards = vec![self.per_frame; frames];
+        if let Some(last) = rewards.last_mut() {
+            *last += if outcome { self.win } else { self.loss };
+        }
+        rewards
+    }
+}
+
+/// How `Model::train` turns a batch into updates
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Algorithm {
+    /// One policy gradient step per batch, weighted by the return or advantage
+    #[default]
+    Reinforce,
+    /// Proximal policy optimization (https://arxiv.org/abs/1707.06347): `epochs`
+    /// steps per batch, each clipping how far the probability of a move can
+    /// move from the one it was played with to within 1 +/- `clip`
+    Ppo { clip: f32, epochs: usize },
+}
+
+/// How an annealed value moves from `start` to `end`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Curve {
+    /// In equal steps
+    #[default]
+    Linear,
+    /// By the same factor every update, so most of the change comes early.
+    /// Both ends need the same sign and neither can be 0, see `Anneal::validate`.
+    Exponential,
+}
+
+/// A value that moves from `start` to `end` over the first `steps` training
+/// updates and stays at `end` after
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Anneal {
+    pub start: f32,
+    pub end: f32,
+    pub steps: u64,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+impl Anneal {
+    /// A value that stays put
+    pub fn constant(value: f32) -> Anneal {
+        Anneal {
+            start: value,
+            end: value,
+            steps: 0,
+            curve: Curve::Linear,
+        }
+    }
+
+    /// Checks the value is a number at every step. An exponential schedule
+    /// can't start or end at 0 or cross it.
+    pub fn validate(&self) -> Result<(), String> {
+        let same_sign =
+            (self.start > 0.0 && self.end > 0.0) || (self.start < 0.0 && self.end < 0.0);
+        if self.curve == Curve::Exponential && !same_sign {
+            return Err(format!(
+                "an exponential schedule can't go from {} to {}",
+                self.start, self.end
+            ));
+        }
+        Ok(())
+    }
+
+    /// The value after `step` updates
+    pub fn at(&self, step: u64) -> f32 {
+        if step >= self.steps {
+            return self.end;
+        }
+        let t = step as f32 / self.steps as f32;
+        match self.curve {
+            Curve::Linear => self.start + (self.end - self.start) * t,
+            Curve::Exponential => self.start * (self.end / self.start).powf(t),
+        }
+    }
+}
+
+/// How far sampling strays from the policy, so that a policy which has
+/// collapsed onto one move still tries the others. Only the policy network
+/// explores this way, DQN has `DqnConfig::epsilon`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Exploration {
+    /// Divides the logits before the softmax: above 1 flattens the
+    /// distribution, below 1 sharpens it
+    pub temperature: Anneal,
+    /// Chance of a uniformly random move instead of one from the policy
+    pub epsilon: Anneal,
+}
+
+impl Default for Exploration {
+    fn default() -> Self {
+        Exploration {
+            temperature: Anneal::constant(1.0),
+            epsilon: Anneal::constant(0.0),
+        }
+    }
+}
+
+/// Hyperparameters only the DQN agent uses, it shares the rest of `TrainConfig`
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DqnConfig {
+    /// Chance of a random move instead of the one with the highest Q-value
+    pub epsilon: f32,
+    /// Frames the replay buffer holds before the oldest games are dropped
+    pub capacity: usize,
+    /// Transitions sampled from the buffer for each update
+    pub minibatch: usize,
+    /// Updates between copies of the online network into the target network
+    pub target_every: u64,
+}
+
+impl Default for DqnConfig {
+    fn default() -> Self {
+        DqnConfig {
+            epsilon: EPSILON,
+            capacity: REPLAY_CAPACITY,
+            minibatch: MINIBATCH,
+            target_every: TARGET_EVERY,
+        }
+    }
+}
+
+/// Hyperparameters for `Model::train`. Unlike the rest of the config these can
+/// change between runs without invalidating a stored model.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TrainConfig {
+    /// Discount applied per frame when computing returns
+    pub gamma: f32,
+    /// Standardize the returns of each batch to zero mean and unit variance
+    pub normalize: bool,
+    #[serde(default)]
+    pub rewards: RewardScheme,
+    #[serde(default)]
+    pub optimizer: OptimizerKind,
+    #[serde(default = "default_learning_rate")]
+    pub learning_rate: f32,
+    /// Shrinks the learning rate as training goes on, constant if unset
+    #[serde(default)]
+    pub decay: Option<Decay>,
+    /// How many games' gradients are summed into each update
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Weight of the value head's loss against the policy's, unused without one
+    #[serde(default = "default_value_coef")]
+    pub value_coef: f32,
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    #[serde(default)]
+    pub dqn: DqnConfig,
+    /// Weight of the policy's entropy, subtracted from the loss to keep it from
+    /// settling on one move too early
+    #[serde(default)]
+    pub entropy_coef: f32,
+    #[serde(default)]
+    pub exploration: Exploration,
+}
+
+fn default_learning_rate() -> f32 {
+    LEARNING_RATE
+}
+
+fn default_batch_size() -> usize {
+    BATCH_SIZE
+}
+
+fn default_value_coef() -> f32 {
+    VALUE_COEF
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        TrainConfig {
+            gamma: GAMMA,
+            normalize: false,
+            rewards: RewardScheme::default(),
+            optimizer: OptimizerKind::default(),
+            learning_rate: LEARNING_RATE,
+            decay: None,
+            batch_size: BATCH_SIZE,
+            value_coef: VALUE_COEF,
+            algorithm: Algorithm::default(),
+            dqn: DqnConfig::default(),
+            entropy_coef: 0.0,
+            exploration: Exploration::default(),
+        }
+    }
+}
+
+/// Which kind of agent plays and learns, each stored under its own key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AgentKind {
+    /// `Model`, which learns its policy directly
+    #[default]
+    PolicyGradient,
+    /// `Dqn`, which learns the value of every move and picks the best
+    Dqn,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Config {
+    pub game: GameConfig,
+    pub model: ModelConfig,
+    #[serde(default)]
+    pub train: TrainConfig,
+    #[serde(default)]
+    pub agent: AgentKind,
+}
+
+impl Config {
+    /// Side of a downsampled frame
+    pub fn frame_dim(&self) -> usize {
+        self.game.quadrants / self.model.resolution
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.frame_dim() * self.frame_dim()
+    }
+
+    /// Size of the network input
+    pub fn input_size(&self) -> usize {
+        self.model.observation.input_size(self.frame_size())
+    }
+
+    /// Channels of the image the conv layers see
+    pub fn input_channels(&self) -> usize {
+        self.model.observation.input_size(1)
+    }
+
+    /// (channels, side) of the output of every conv layer
+    pub fn conv_shapes(&self) -> Vec<(usize, usize)> {
+        let mut dim = self.frame_dim();
+        self.model
+            .conv
+            .iter()
+            .map(|conv| {
+                dim = conv.output_dim(dim);
+                (conv.channels, dim)
+            })
+            .collect()
+    }
+
+    /// Size of the first hidden layer's input: the flattened output of the
+    /// conv layers, or the observation without any
+    pub fn dense_input_size(&self) -> usize {
+        self.conv_shapes()
+            .last()
+            .map_or(self.input_size(), |(channels, dim)| channels * dim * dim)
+    }
+
+    /// Checks that a network can be built from the config
+    pub fn validate(&self) -> Result<(), String> {
+        let exploration = self.train.exploration;
+        exploration
+            .temperature
+            .validate()
+            .map_err(|e| format!("temperature: {}", e))?;
+        exploration
+            .epsilon
+            .validate()
+            .map_err(|e| format!("epsilon: {}", e))?;
+        if self.agent == AgentKind::Dqn && exploration != Exploration::default() {
+            return Err("DQN explores with dqn.epsilon, not exploration".to_string());
+        }
+        if self.model.observation.frames() == 0 {
+            return Err("an observation needs at least one frame".to_string());
+        }
+        if !self.model.conv.is_empty() && !self.model.observation.is_image() {
+            return Err(format!(
+                "conv layers need the board as an image, not {:?}",
+                self.model.observation
+            ));
+        }
+        if self.model.resolution == 0 || self.frame_dim() == 0 {
+            return Err("the board is pooled down to nothing".to_string());
+        }
+        let shapes = self.conv_shapes();
+        for (i, (conv, (_, dim))) in self.model.conv.iter().zip(shapes).enumerate() {
+            if conv.kernel == 0 || conv.channels == 0 || dim == 0 {
+                return Err(format!(
+                    "conv layer {} leaves nothing of the {}x{} board",
+                    i + 1,
+                    self.frame_dim(),
+                    self.frame_dim()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a model trained with `self` can be used with `current`.
+    ///
+    /// A different board size or resolution changes the frames the model sees,
+    /// so the weights won't line up and this fails. If only the physics differ
+    /// the model still runs, so it just warns.
+    pub fn check(&self, current: &Config) -> Result<(), String> {
+        if self.game.quadrants != current.game.quadrants
+            || self.model.resolution != current.model.resolution
+        {
+            return Err(format!(
+                "model was trained on a {}x{} board pooled by {}, not {}x{} pooled by {}",
+                self.game.quadrants,
+                self.game.quadrants,
+                self.model.resolution,
+                current.game.quadrants,
+                current.game.quadrants,
+                current.model.resolution
+            ));
+        }
+        if self.game != current.game {
+            logging::log(&format!(
+                "warning: model was trained with {:?}, playing with {:?}",
+                self.game, current.game
+            ));
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<Config> = RefCell::new(Config::default());
+}
+
+/// The config new games and models are set up with
+pub fn current() -> Config {
+    CONFIG.with(|config| config.borrow().clone())
+}
+
+pub fn set_current(config: Config) {
+    CONFIG.with(|current| *current.borrow_mut() = config);
+}
+
+#[wasm_bindgen]
+pub fn default_config() -> Result<JsValue, JsValue> {
+    Ok(serde_wasm_bindgen::to_value(&Config::default())?)
+}
+
+#[wasm_bindgen]
+pub fn get_config() -> Result<JsValue, JsValue> {
+    Ok(serde_wasm_bindgen::to_value(&current())?)
+}
+
+/// Replaces the config, e.g. with one saved in local storage at startup.
+/// Refused if no network could be built from it.
+#[wasm_bindgen]
+pub fn set_config(config: JsValue) -> Result<(), JsValue> {
+    let config: Config = serde_wasm_bindgen::from_value(config)?;
+    config.validate()?;
+    set_current(config);
+    Ok(())
+}
+
+/// Sets how many finished games `handle_end` waits for before training
+#[wasm_bindgen]
+pub fn set_batch_size(batch_size: usize) {
+    CONFIG.with(|config| config.borrow_mut().train.batch_size = batch_size.max(1));
+}
+
+/// Switches between the policy gradient agent ("PolicyGradient") and the DQN
+/// agent ("Dqn"), each keeping its own stored weights
+#[wasm_bindgen]
+pub fn set_agent(agent: JsValue) -> Result<(), JsValue> {
+    let agent = serde_wasm_bindgen::from_value(agent)?;
+    CONFIG.with(|config| config.borrow_mut().agent = agent);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anneal(start: f32, end: f32, curve: Curve) -> Anneal {
+        Anneal {
+            start,
+            end,
+            steps: 100,
+            curve,
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn linear_anneal_moves_in_equal_steps() {
+        let linear = anneal(1.0, 0.0, Curve::Linear);
+        assert_close(linear.at(0), 1.0);
+        assert_close(linear.at(25), 0.75);
+        assert_close(linear.at(50), 0.5);
+    }
+
+    #[test]
+    fn exponential_anneal_moves_by_the_same_factor() {
+        // halfway from 1 to 0.01 is 0.1
+        let exponential = anneal(1.0, 0.01, Curve::Exponential);
+        assert_close(exponential.at(0), 1.0);
+        assert_close(exponential.at(50), 0.1);
+    }
+
+    #[test]
+    fn anneal_stays_at_the_end() {
+        for &curve in &[Curve::Linear, Curve::Exponential] {
+            let anneal = anneal(1.0, 0.5, curve);
+            assert_close(anneal.at(100), 0.5);
+            assert_close(anneal.at(1000), 0.5);
+        }
+        assert_close(Anneal::constant(0.3).at(0), 0.3);
+    }
+
+    #[test]
+    fn exponential_anneal_through_zero_is_refused() {
+        assert!(anneal(0.0, 0.5, Curve::Exponential).validate().is_err());
+        assert!(anneal(0.5, 0.0, Curve::Exponential).validate().is_err());
+        assert!(anneal(-1.0, 1.0, Curve::Exponential).validate().is_err());
+        assert!(anneal(0.0, 0.5, Curve::Linear).validate().is_ok());
+        assert!(anneal(-1.0, -0.5, Curve::Exponential).validate().is_ok());
+    }
+}
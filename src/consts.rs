@@ -6,4 +6,20 @@ pub const MODEL_STORE: &str = "model";
 pub const STATE_STORE: &str = "lifecycle";
 pub const MODEL_DB_KEY: &str = "id";
 pub const MODEL_DB_KEY_VERSION: f64 = 0.0;
+pub const DQN_DB_KEY: f64 = 1.0;
+pub const REPLAY_DB_KEY: f64 = 2.0;
 pub const STATE_DB_KEY: &str = "id";
+pub const PADDLE_WIDTH: f64 = 1.0;
+pub const PADDLE_HEIGHT: f64 = 20.0;
+pub const PADDLE_SPEED: f64 = 3.0;
+pub const BALL_SIZE: f64 = 2.0;
+pub const BALL_DX: f64 = 2.5;
+pub const BALL_DY: f64 = 2.5;
+pub const GAMMA: f32 = 0.99;
+pub const LEARNING_RATE: f32 = 1e-4;
+pub const BATCH_SIZE: usize = 10;
+pub const VALUE_COEF: f32 = 0.5;
+pub const EPSILON: f32 = 0.1;
+pub const REPLAY_CAPACITY: usize = 10_000;
+pub const MINIBATCH: usize = 32;
+pub const TARGET_EVERY: u64 = 100;
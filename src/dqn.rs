@@ -0,0 +1,330 @@
+use crate::{
+    config::{Config, TrainConfig},
+    consts::DQN_DB_KEY,
+    logging,
+    model::{Inference, Model, ModelError, ModelSerializer, TrainStats, ACTIONS},
+    observation::Frame,
+    returns::discounted_returns,
+    rng::{self, ModelRng},
+    state::{Distribution, Sequence},
+};
+
+use candle_core::{Device, Tensor};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use wasm_bindgen::prelude::*;
+use web_sys::js_sys::Object;
+
+/// The frames of the most recent games, so training can sample transitions
+/// out of order instead of following one game at a time
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ReplayBuffer {
+    games: VecDeque<Sequence>,
+    /// Frames across `games`
+    frames: usize,
+}
+
+impl ReplayBuffer {
+    /// Adds a finished game, dropping the oldest games once the buffer holds
+    /// more than `capacity` frames. The newest game is always kept, and an
+    /// unfinished one is never added.
+    pub fn push(&mut self, game: Sequence, capacity: usize) {
+        if game.len() == 0 || !game.is_finished() {
+            return;
+        }
+        self.frames += game.len();
+        self.games.push_back(game);
+        while self.frames > capacity && self.games.len() > 1 {
+            if let Some(oldest) = self.games.pop_front() {
+                self.frames -= oldest.len();
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    /// `n` frames drawn uniformly with replacement, each as the index of its
+    /// game, see `game`, and its index in that game
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R, n: usize) -> Vec<(usize, usize)> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        (0..n)
+            .filter_map(|_| self.frame(rng.gen_range(0..self.frames)))
+            .collect()
+    }
+
+    /// The game at `index`, oldest first
+    pub fn game(&self, index: usize) -> &Sequence {
+        &self.games[index]
+    }
+
+    fn frame(&self, mut index: usize) -> Option<(usize, usize)> {
+        for (i, game) in self.games.iter().enumerate() {
+            if index < game.len() {
+                return Some((i, index));
+            }
+            index -= game.len();
+        }
+        None
+    }
+}
+
+/// A deep Q-network agent (https://arxiv.org/abs/1312.5602).
+///
+/// The online network has the same shape as the policy network, but its
+/// outputs are read as the return expected after each move rather than logits.
+/// It plays epsilon-greedy and learns from transitions sampled out of the
+/// replay buffer, towards targets from a copy of itself that's only refreshed
+/// every `DqnConfig::target_every` updates.
+#[derive(Clone)]
+pub struct Dqn {
+    online: Model,
+    target: Model,
+    buffer: ReplayBuffer,
+    /// Updates so far, for refreshing the target network
+    updates: u64,
+    /// Samples the replay buffer
+    rng: ModelRng,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DqnSerializer {
+    /// The agent's key in the model store
+    id: u8,
+    online: ModelSerializer,
+    target: ModelSerializer,
+    #[serde(default)]
+    buffer: ReplayBuffer,
+    updates: u64,
+}
+
+impl Dqn {
+    pub fn new<R: Rng + ?Sized>(config: Config, rng: &mut R) -> Dqn {
+        let online = Model::new(config, rng);
+        Dqn {
+            target: online.clone(),
+            online,
+            buffer: ReplayBuffer::default(),
+            updates: 0,
+            rng: rng::seeded(rng.gen()),
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        DQN_DB_KEY as u8
+    }
+
+    pub fn config(&self) -> &Config {
+        self.online.config()
+    }
+
+    pub fn set_train_config(&mut self, train: TrainConfig) {
+        self.online.set_train_config(train);
+        self.target.set_train_config(train);
+    }
+
+    /// How many recent frames `infer` wants to see
+    pub fn history(&self) -> usize {
+        self.online.history()
+    }
+
+    pub fn buffer(&self) -> &ReplayBuffer {
+        &self.buffer
+    }
+
+    pub fn set_buffer(&mut self, buffer: ReplayBuffer) {
+        self.buffer = buffer;
+    }
+
+    pub fn serialize(&self) -> Result<DqnSerializer, candle_core::Error> {
+        Ok(DqnSerializer {
+            id: self.id(),
+            online: self.online.serialize()?,
+            target: self.target.serialize()?,
+            buffer: self.buffer.clone(),
+            updates: self.updates,
+        })
+    }
+
+    /// Rebuilds a stored agent, checking both networks like
+    /// `Model::deserialize` does and that they have the same shape
+    pub fn deserialize(dqn: DqnSerializer) -> Result<Dqn, ModelError> {
+        let online = Model::deserialize(dqn.online)?;
+        let target = Model::deserialize(dqn.target)?;
+        if online.config().model != target.config().model {
+            return Err(ModelError::Architecture(
+                "the target network doesn't match the online network".to_string(),
+            ));
+        }
+        Ok(Dqn {
+            online,
+            target,
+            buffer: dqn.buffer,
+            updates: dqn.updates,
+            rng: rng::from_entropy(),
+        })
+    }
+
+    pub fn from_jsobject(dqn: JsValue) -> Result<Dqn, ModelError> {
+        Dqn::deserialize(serde_wasm_bindgen::from_value(dqn)?)
+    }
+
+    /// The agent as its record in the model store, without the replay buffer.
+    /// The buffer is stored on its own, since only training needs it and
+    /// every move reads the agent.
+    pub fn to_jsobject(&self) -> Result<Object, JsValue> {
+        let dqn = DqnSerializer {
+            id: self.id(),
+            online: self
+                .online
+                .serialize()
+                .map_err(|e| JsValue::from(e.to_string()))?,
+            target: self
+                .target
+                .serialize()
+                .map_err(|e| JsValue::from(e.to_string()))?,
+            buffer: ReplayBuffer::default(),
+            updates: self.updates,
+        };
+        Ok(serde_wasm_bindgen::to_value(&dqn)?.unchecked_into())
+    }
+
+    /// Picks the move with the highest Q-value, or with a chance of
+    /// `DqnConfig::epsilon` a random one
+    pub fn infer<R: Rng + ?Sized>(&self, frames: &[Frame], rng: &mut R) -> Inference {
+        let mut infer_wrapper = || -> Result<Inference, candle_core::Error> {
+            let input = Tensor::from_vec(
+                self.online.observe(frames),
+                (1, self.config().input_size()),
+                &Device::Cpu,
+            )?;
+            let best = self.online.forward(&input)?.argmax(1)?.to_vec1::<u32>()?[0] as usize;
+            // every move gets its share of epsilon, the best one gets the rest,
+            // so the stored distribution is what the move was drawn from
+            let epsilon = self.config().train.dqn.epsilon.clamp(0.0, 1.0);
+            let mut p = [epsilon / ACTIONS as f32; ACTIONS];
+            p[best] += 1.0 - epsilon;
+            let dist = Distribution::new(p[0], p[1], p[2]);
+            let choice = dist.sample(rng);
+            Ok(Inference {
+                dist,
+                choice,
+                entropy: None,
+                probability: None,
+            })
+        };
+        infer_wrapper().unwrap_or_else(|e| {
+            logging::error(&e.to_string());
+            Inference {
+                dist: Distribution::new(0.0, 0.0, 0.0),
+                choice: 0,
+                entropy: None,
+                probability: None,
+            }
+        })
+    }
+
+    /// Adds the finished games of a batch to the replay buffer, then runs one
+    /// update per `DqnConfig::minibatch` new frames so every frame is sampled
+    /// about once while it's new
+    pub fn train(&mut self, batch: &[Sequence]) -> Option<TrainStats> {
+        let train = self.config().train;
+        let batch = batch
+            .iter()
+            .filter(|seq| seq.is_finished())
+            .collect::<Vec<_>>();
+        let returns = batch
+            .iter()
+            .flat_map(|seq| discounted_returns(&seq.rewards(), train.gamma))
+            .collect::<Vec<_>>();
+        if returns.is_empty() {
+            return None;
+        }
+        for seq in batch {
+            self.buffer.push(Sequence::clone(seq), train.dqn.capacity);
+        }
+        let updates = (returns.len() / train.dqn.minibatch.max(1)).max(1);
+        let mut train_wrapper = || -> Result<f32, candle_core::Error> {
+            let mut loss = 0.0;
+            for _ in 0..updates {
+                loss += self.update()?;
+            }
+            Ok(loss / updates as f32)
+        };
+        match train_wrapper() {
+            Ok(q_loss) => {
+                let stats = TrainStats {
+                    frames: returns.len(),
+                    mean_return: returns.iter().sum::<f32>() / returns.len() as f32,
+                    q_loss: Some(q_loss),
+                    ..TrainStats::default()
+                };
+                logging::log(&stats.to_string());
+                Some(stats)
+            }
+            Err(e) => {
+                logging::error(&e.to_string());
+                None
+            }
+        }
+    }
+
+    /// One update on a minibatch from the replay buffer, moving Q(s, a)
+    /// towards r + gamma * max Q_target(s', a'), or just r on a game's last
+    /// frame. Returns the mean squared error before the update.
+    fn update(&mut self) -> Result<f32, candle_core::Error> {
+        let train = self.config().train;
+        let history = self.history();
+        let input_size = self.config().input_size();
+        let minibatch = train.dqn.minibatch.max(1);
+
+        let mut inputs = Vec::with_capacity(minibatch * input_size);
+        let mut next_inputs = Vec::with_capacity(minibatch * input_size);
+        let mut actions = Vec::with_capacity(minibatch);
+        let mut rewards = Vec::with_capacity(minibatch);
+        let mut discounts = Vec::with_capacity(minibatch);
+        // games are often sampled more than once, so reward each of them once
+        let mut game_rewards = HashMap::new();
+        for (game, i) in self.buffer.sample(&mut self.rng, minibatch) {
+            let seq = self.buffer.game(game);
+            let done = i + 1 == seq.len();
+            let next = if done { i } else { i + 1 };
+            inputs.extend(self.online.observe(&seq.frames_at(i, history)));
+            next_inputs.extend(self.online.observe(&seq.frames_at(next, history)));
+            actions.push(seq.get_sequence()[i].to_tuple().1.choice as u32);
+            rewards.push(game_rewards.entry(game).or_insert_with(|| seq.rewards())[i]);
+            discounts.push(if done { 0.0 } else { train.gamma });
+        }
+        let n = actions.len();
+        if n == 0 {
+            return Ok(0.0);
+        }
+        let device = Device::Cpu;
+        let inputs = Tensor::from_vec(inputs, (n, input_size), &device)?;
+        let next_inputs = Tensor::from_vec(next_inputs, (n, input_size), &device)?;
+        let actions = Tensor::from_vec(actions, (n, 1), &device)?;
+        let rewards = Tensor::from_vec(rewards, (n, 1), &device)?;
+        let discounts = Tensor::from_vec(discounts, (n, 1), &device)?;
+
+        let q = self.online.forward(&inputs)?.gather(&actions, 1)?;
+        let next_q = self.target.forward(&next_inputs)?.max_keepdim(1)?.detach();
+        let targets = rewards.add(&next_q.mul(&discounts)?)?;
+        let loss = q.sub(&targets)?.sqr()?.sum_all()?;
+        let mean = loss.to_scalar::<f32>()? / n as f32;
+        self.online.step(&loss)?;
+
+        self.updates += 1;
+        if self.updates.is_multiple_of(train.dqn.target_every.max(1)) {
+            self.target = self.online.clone();
+        }
+        Ok(mean)
+    }
+}
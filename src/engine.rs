@@ -0,0 +1,234 @@
+use crate::config::GameConfig;
+
+use serde::{Deserialize, Serialize};
+
+/// A move for a paddle, numbered the way `index.js` reads the worker's choice
+/// (1 moves up, 2 moves down, anything else leaves the paddle in place)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Action {
+    Stay,
+    Up,
+    Down,
+}
+
+impl From<u8> for Action {
+    fn from(choice: u8) -> Action {
+        match choice {
+            1 => Action::Up,
+            2 => Action::Down,
+            _ => Action::Stay,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Player {
+    One,
+    Two,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Paddle {
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+    pub speed: f64,
+}
+
+impl Paddle {
+    pub fn new(x: f64, y: f64, config: &GameConfig) -> Paddle {
+        Paddle {
+            x,
+            y,
+            w: config.paddle_width,
+            h: config.paddle_height,
+            speed: config.paddle_speed,
+        }
+    }
+
+    /// Moves the paddle, with the same (slightly leaky) bounds checks as `movePlayer`
+    pub fn apply(&mut self, action: Action, height: f64) {
+        match action {
+            Action::Up if self.y > 0.0 => self.y -= self.speed,
+            Action::Down if self.y < height - self.h => self.y += self.speed,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ball {
+    pub x: f64,
+    pub y: f64,
+    pub size: f64,
+    pub dx: f64,
+    pub dy: f64,
+}
+
+impl Ball {
+    pub fn new(config: &GameConfig) -> Ball {
+        let side = config.quadrants as f64;
+        Ball {
+            x: side / 2.0,
+            y: side / 2.0,
+            size: config.ball_size,
+            dx: config.ball_dx,
+            dy: config.ball_dy / 2.0,
+        }
+    }
+}
+
+/// A headless version of the game in `index.js`.
+///
+/// Everything is measured in board cells rather than pixels, so the board is
+/// `quadrants` x `quadrants` and the paddle/ball sizes are the raw `paddleConfig`
+/// and `ballConfig` values.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GameState {
+    pub config: GameConfig,
+    pub width: f64,
+    pub height: f64,
+    pub p1: Paddle,
+    pub p2: Paddle,
+    pub ball: Ball,
+    pub p1_score: u32,
+    pub p2_score: u32,
+    /// The paddle the ball bounced off during the last step, if any
+    pub hit: Option<Player>,
+}
+
+impl Default for GameState {
+    fn default() -> Self {
+        Self::new(GameConfig::default())
+    }
+}
+
+impl GameState {
+    pub fn new(config: GameConfig) -> GameState {
+        let width = config.quadrants as f64;
+        let height = config.quadrants as f64;
+        GameState {
+            config,
+            width,
+            height,
+            p1: Paddle::new(0.0, height / 2.0, &config),
+            p2: Paddle::new(width - config.paddle_width, height / 2.0, &config),
+            ball: Ball::new(&config),
+            p1_score: 0,
+            p2_score: 0,
+            hit: None,
+        }
+    }
+
+    /// Where the ball is and where it's going, then where both paddles are, for
+    /// `Features::from_positions`
+    pub fn positions(&self) -> [f64; 6] {
+        [
+            self.ball.x,
+            self.ball.y,
+            self.ball.dx,
+            self.ball.dy,
+            self.p1.y,
+            self.p2.y,
+        ]
+    }
+
+    /// Puts the ball back in the middle, the paddles stay where they are
+    pub fn reset(&mut self) {
+        self.ball = Ball::new(&self.config);
+    }
+
+    /// Advances the game by one tick of `gameLoop`.
+    ///
+    /// The paddles move first (the worker answers the previous frame before the
+    /// next `update`), then the ball moves and bounces as in `update` and
+    /// `handleBallCollisions`. Returns the player that scored, if any.
+    pub fn step(&mut self, p1_action: Action, p2_action: Action) -> Option<Player> {
+        self.p1.apply(p1_action, self.height);
+        self.p2.apply(p2_action, self.height);
+
+        self.ball.x += self.ball.dx;
+        self.ball.y += self.ball.dy;
+        self.hit = self.handle_ball_collisions();
+
+        if self.ball.x + self.ball.size <= 0.0 {
+            self.p2_score += 1;
+            self.reset();
+            return Some(Player::Two);
+        }
+        if self.ball.x - self.ball.size >= self.width {
+            self.p1_score += 1;
+            self.reset();
+            return Some(Player::One);
+        }
+        None
+    }
+
+    fn handle_ball_collisions(&mut self) -> Option<Player> {
+        let ball = &mut self.ball;
+        if ball.y - ball.size <= 0.0 || ball.y + ball.size >= self.height {
+            ball.dy = -ball.dy;
+        }
+
+        let (p1, p2) = (&self.p1, &self.p2);
+        let mut hit = None;
+        if ball.x - ball.size <= p1.x + p1.w && ball.y >= p1.y && ball.y <= p1.y + p1.h {
+            ball.dx = -ball.dx;
+            let delta_y = ball.y - (p1.y + p1.h / 2.0);
+            ball.dy = delta_y * 0.25;
+            hit = Some(Player::One);
+        }
+        if ball.x + ball.size >= p2.x && ball.y >= p2.y && ball.y <= p2.y + p2.h {
+            ball.dx = -ball.dx;
+            let delta_y = ball.y - (p2.y + p2.h / 2.0);
+            ball.dy = delta_y * 0.25;
+            hit = Some(Player::Two);
+        }
+        hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A still paddle for player one at y 90..110, with the ball at (x, y)
+    /// heading straight for it
+    fn game_with_ball(x: f64, y: f64) -> GameState {
+        let mut game = GameState::default();
+        game.p1.y = 90.0;
+        game.ball.x = x;
+        game.ball.y = y;
+        game.ball.dy = 0.0;
+        game.ball.dx = -game.config.ball_dx;
+        game
+    }
+
+    #[test]
+    fn paddle_hit_reflects_the_ball() {
+        let mut game = game_with_ball(3.0, 104.0);
+        assert_eq!(game.step(Action::Stay, Action::Stay), None);
+        assert_eq!(game.hit, Some(Player::One));
+        assert_eq!(game.ball.dx, game.config.ball_dx);
+        // 4 cells below the paddle's middle
+        assert_eq!(game.ball.dy, 1.0);
+    }
+
+    #[test]
+    fn missed_ball_scores_and_resets() {
+        let mut game = game_with_ball(0.0, 10.0);
+        assert_eq!(game.step(Action::Stay, Action::Stay), Some(Player::Two));
+        assert_eq!((game.p1_score, game.p2_score), (0, 1));
+        assert_eq!(game.hit, None);
+        assert_eq!((game.ball.x, game.ball.y), (100.0, 100.0));
+    }
+
+    #[test]
+    fn choices_map_to_actions_like_index_js() {
+        assert_eq!(Action::from(0), Action::Stay);
+        assert_eq!(Action::from(1), Action::Up);
+        assert_eq!(Action::from(2), Action::Down);
+        assert_eq!(Action::from(7), Action::Stay);
+    }
+}
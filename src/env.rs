@@ -0,0 +1,178 @@
+use crate::{
+    agent::Agent,
+    config::Config,
+    engine::{Action, GameState, Player},
+    observation::{push_frame, Frame},
+    rng::ModelRng,
+};
+
+/// How many frames `action_entropy` averages over
+pub const ENTROPY_FRAMES: usize = 1000;
+
+/// A gym-style environment: `reset` for the first observation, then `step`
+/// with actions until it reports `done`
+pub trait Environment {
+    type Observation;
+    type Action;
+    type Info;
+
+    fn reset(&mut self) -> Self::Observation;
+    fn step(&mut self, action: Self::Action) -> (Self::Observation, f32, bool, Self::Info);
+}
+
+/// Extra detail about a Pong episode so far
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Info {
+    /// Times the agent's paddle has returned the ball
+    pub hits: u32,
+    /// Paddle hits by either side since the serve
+    pub rally: u32,
+}
+
+/// Picks the move for player two given the whole game
+pub type Opponent = Box<dyn FnMut(&GameState) -> Action>;
+
+/// A scripted opponent that keeps the middle of its paddle level with the ball
+pub fn tracking_opponent() -> Opponent {
+    Box::new(|game: &GameState| {
+        let center = game.p2.y + game.p2.h / 2.0;
+        match game.ball.y - center {
+            d if d < -game.p2.speed => Action::Up,
+            d if d > game.p2.speed => Action::Down,
+            _ => Action::Stay,
+        }
+    })
+}
+
+/// An opponent that plays player two's mirrored view with an agent, as in "train" mode.
+/// It remembers the frames it has seen, so make a new one for every point.
+pub fn model_opponent(model: Box<dyn Agent>, mut rng: ModelRng) -> Opponent {
+    let mut frames = Vec::new();
+    let keep = model.history();
+    let resolution = model.config().model.resolution;
+    Box::new(move |game: &GameState| {
+        push_frame(
+            &mut frames,
+            Frame::from_game(game, Player::Two, resolution),
+            keep,
+        );
+        model.infer(&frames, &mut rng).choice.into()
+    })
+}
+
+/// Pong from player one's side: one episode is one point, worth +1 if player
+/// one wins it and -1 if it loses
+pub struct Pong {
+    game: GameState,
+    opponent: Opponent,
+    info: Info,
+    resolution: usize,
+}
+
+impl Pong {
+    pub fn new(config: &Config, opponent: Opponent) -> Pong {
+        Pong {
+            game: GameState::new(config.game),
+            opponent,
+            info: Info::default(),
+            resolution: config.model.resolution,
+        }
+    }
+
+    pub fn set_opponent(&mut self, opponent: Opponent) {
+        self.opponent = opponent;
+    }
+
+    pub fn game(&self) -> &GameState {
+        &self.game
+    }
+}
+
+impl Environment for Pong {
+    type Observation = Frame;
+    type Action = Action;
+    type Info = Info;
+
+    fn reset(&mut self) -> Frame {
+        self.game.reset();
+        self.info = Info::default();
+        Frame::from_game(&self.game, Player::One, self.resolution)
+    }
+
+    fn step(&mut self, action: Action) -> (Frame, f32, bool, Info) {
+        let opponent = (self.opponent)(&self.game);
+        let point = self.game.step(action, opponent);
+        if let Some(player) = self.game.hit {
+            self.info.rally += 1;
+            if player == Player::One {
+                self.info.hits += 1;
+            }
+        }
+        let reward = match point {
+            Some(Player::One) => 1.0,
+            Some(Player::Two) => -1.0,
+            None => 0.0,
+        };
+        (
+            Frame::from_game(&self.game, Player::One, self.resolution),
+            reward,
+            point.is_some(),
+            self.info,
+        )
+    }
+}
+
+/// The summary of a finished episode
+#[derive(Clone, Debug)]
+pub struct Rollout<I> {
+    pub reward: f32,
+    pub steps: usize,
+    pub info: I,
+}
+
+/// Runs one episode with `policy` picking the actions. Gives up and returns
+/// None if the episode isn't done after `max_steps`.
+pub fn rollout<E, P>(env: &mut E, mut policy: P, max_steps: usize) -> Option<Rollout<E::Info>>
+where
+    E: Environment,
+    P: FnMut(&E::Observation) -> E::Action,
+{
+    let mut obs = env.reset();
+    let mut reward = 0.0;
+    for steps in 1..=max_steps {
+        let (next, r, done, info) = env.step(policy(&obs));
+        reward += r;
+        if done {
+            return Some(Rollout {
+                reward,
+                steps,
+                info,
+            });
+        }
+        obs = next;
+    }
+    None
+}
+
+/// The mean entropy of the model's policy over `frames` frames of it playing the
+/// tracking opponent. A fresh model should start close to ln 3 (about 1.099),
+/// picking every move about as often, or it has nothing to learn from.
+pub fn action_entropy(model: &dyn Agent, rng: &mut ModelRng, frames: usize) -> f32 {
+    let mut pong = Pong::new(model.config(), tracking_opponent());
+    let mut history = Vec::new();
+    let mut obs = pong.reset();
+    let mut total = 0.0;
+    for _ in 0..frames {
+        push_frame(&mut history, obs, model.history());
+        let inference = model.infer(&history, rng);
+        total += inference.policy_entropy();
+        let (next, _, done, _) = pong.step(inference.choice.into());
+        obs = if done {
+            history.clear();
+            pong.reset()
+        } else {
+            next
+        };
+    }
+    total / frames.max(1) as f32
+}
@@ -1,12 +1,33 @@
+pub mod agent;
+pub mod ascii;
+pub mod bench;
+pub mod config;
 pub mod consts;
+pub mod dqn;
+pub mod engine;
+pub mod env;
+pub mod logging;
 pub mod model;
+pub mod observation;
+pub mod optim;
+pub mod raster;
+pub mod replay;
+pub mod returns;
+pub mod rng;
 pub mod state;
 
-use crate::state::{add_frame, end_game, read_model, read_unprocessed_states, write_model, State};
+use crate::{
+    engine::Player,
+    observation::{Features, Frame},
+    replay::Replay,
+    state::{
+        end_game, get_current_game, mark_processed, read_agent, read_model, read_sequence,
+        read_unprocessed_states, write_agent, write_current_game, write_model, State, StoreError,
+    },
+};
 
-use rexie::Error;
 use serde::{Deserialize, Serialize};
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, convert::TryInto, rc::Rc};
 use wasm_bindgen::prelude::*;
 use web_sys::{console, MessageEvent, Worker};
 
@@ -35,59 +56,166 @@ pub fn startup() {
     closure.forget(); // Keep the closure alive
 }
 
+/// Picks a move for `player` (0 or 1) from its view of the board, saving the
+/// frame to the current game if `save` is set. The config's `agent` decides
+/// which agent plays.
+///
+/// `positions` are the raw positions `Features` are built from, from player
+/// one's side whichever player is moving. Without them, or with the wrong
+/// number, a model that observes features sees zeros.
 #[wasm_bindgen]
-pub async fn handle_img(img: Vec<u8>, save: bool) -> u8 {
+pub async fn handle_img(
+    img: Vec<u8>,
+    positions: Option<Vec<f64>>,
+    player: usize,
+    save: bool,
+) -> u8 {
     let handle_img_wrapper = async {
-        let model = read_model().await.unwrap_or_else(|e| {
-            web_sys::console::log_1(&format!("{:?}", e).into());
-            model::Model::new()
-        });
-        let inference = model.infer(img.clone());
+        // a stored agent that can't be read doesn't play, so the game isn't
+        // recorded against a new one
+        let model = read_agent(false).await?;
+        let mut game = get_current_game().await?;
+        let history = model.history();
+        let side = if player == 0 {
+            Player::One
+        } else {
+            Player::Two
+        };
+        let features = positions
+            .and_then(|positions| positions.try_into().ok())
+            .map(|positions| Features::from_positions(positions, side, &model.config().game));
+        let frame = Frame::new(img, features);
+        let frames = game.observe(player, frame.clone(), history);
+        let inference = model.infer(&frames, &mut rng::from_entropy());
         let inference_choice = inference.choice;
         if save {
-            add_frame(State::new(img, inference))
-                .await
-                .unwrap_or_else(|e| {
-                    web_sys::console::log_1(&format!("{:?}", e).into());
-                });
+            game.record_agent(model.config(), model.id());
+            game.push(State::new(frame, inference));
         }
-        Ok::<u8, Error>(inference_choice)
+        if save || history > 1 {
+            write_current_game(game).await.unwrap_or_else(|e| {
+                web_sys::console::log_1(&format!("{:?}", e).into());
+            });
+        }
+        Ok::<u8, StoreError>(inference_choice)
     };
     match handle_img_wrapper.await {
         Ok(choice) => choice,
         Err(e) => {
-            web_sys::console::log_1(&format!("{:?}", e).into());
+            web_sys::console::error_1(&e.to_string().into());
             0
         }
     }
 }
 
+/// What `handle_end` did with the finished games
+enum Training {
+    /// Fewer games than a batch are waiting, this many more are needed
+    Waiting(usize),
+    /// This many batches updated the agent
+    Trained(usize),
+}
+
 #[wasm_bindgen]
 pub async fn handle_end(outcome: bool) {
     let train_wrapper = async {
         end_game(outcome).await.unwrap_throw();
-        let mut model = read_model().await.unwrap_or_else(|e| {
-            web_sys::console::log_1(&format!("{:?}", e).into());
-            model::Model::new()
-        });
+        // a stored agent that can't be read is left alone, and so are the games
+        let mut model = read_agent(true).await?;
         let unprocessed_states = read_unprocessed_states().await.unwrap_or_else(|e| {
             web_sys::console::log_1(&format!("{:?}", e).into());
             vec![]
         });
-        unprocessed_states.iter().for_each(|state| {
-            model.train(state);
-        });
-        let _ = write_model(model).await.unwrap_or_else(|e| {
-            web_sys::console::log_1(&format!("{:?}", e).into());
-        });
-        Ok::<(), Error>(())
+        // train once a full batch of games is waiting, leaving any stragglers
+        // for the next batch
+        let batch_size = model.config().train.batch_size.max(1);
+        if unprocessed_states.len() < batch_size {
+            return Ok(Training::Waiting(batch_size - unprocessed_states.len()));
+        }
+        let trained = unprocessed_states.len() / batch_size * batch_size;
+        let updates = unprocessed_states[..trained]
+            .chunks(batch_size)
+            .filter_map(|batch| model.train(batch))
+            .count();
+        write_agent(model.as_ref()).await?;
+        mark_processed(unprocessed_states.into_iter().take(trained).collect()).await?;
+        Ok::<Training, StoreError>(Training::Trained(updates))
     };
     match train_wrapper.await {
-        Ok(_) => {
-            web_sys::console::log_1(&"Training successful".into());
+        Ok(Training::Waiting(games)) => {
+            web_sys::console::log_1(&format!("Waiting for {} more games to train", games).into());
+        }
+        Ok(Training::Trained(0)) => {
+            web_sys::console::log_1(&"Training ran but had nothing to learn from".into());
+        }
+        Ok(Training::Trained(batches)) => {
+            web_sys::console::log_1(&format!("Training successful, {} batches", batches).into());
         }
         Err(e) => {
-            web_sys::console::log_1(&format!("{:?}", e).into());
+            web_sys::console::error_1(&format!("Training failed, {}", e).into());
         }
     }
 }
+
+/// The mean action entropy of a fresh model with the current config, to check
+/// it starts out close to picking uniformly (ln 3, about 1.099)
+#[wasm_bindgen]
+pub fn initial_entropy() -> f32 {
+    let model = agent::new_agent(config::current(), &mut rng::from_entropy());
+    env::action_entropy(
+        model.as_ref(),
+        &mut rng::from_entropy(),
+        env::ENTROPY_FRAMES,
+    )
+}
+
+/// Milliseconds per move for the current config as an MLP and as a CNN, see
+/// `bench::compare`
+#[wasm_bindgen]
+pub fn benchmark_inference(iterations: usize) -> Result<JsValue, JsValue> {
+    Ok(serde_wasm_bindgen::to_value(&bench::compare(
+        &config::current(),
+        iterations,
+    ))?)
+}
+
+/// Encodes a stored game as a replay file, e.g. for a browser download.
+/// Refused for games stored before they recorded the agent that played them.
+#[wasm_bindgen]
+pub async fn export_replay(id: f64) -> Result<Vec<u8>, JsValue> {
+    let seq = read_sequence(id)
+        .await
+        .map_err(|e| JsValue::from(format!("{:?}", e)))?
+        .ok_or_else(|| JsValue::from(format!("No game with id {}", id)))?;
+    let (config, model_id) = seq
+        .get_agent()
+        .ok_or_else(|| JsValue::from(format!("Game {} doesn't record its agent", id)))?;
+    Replay::from_sequence(&seq, config.clone(), model_id, None)
+        .to_bytes()
+        .map_err(|e| JsValue::from(e.to_string()))
+}
+
+/// The stored model as a safetensors file, e.g. for a browser download
+#[wasm_bindgen]
+pub async fn export_model() -> Result<Vec<u8>, JsValue> {
+    let model = read_model()
+        .await
+        .map_err(|e| JsValue::from(e.to_string()))?;
+    model
+        .to_safetensors()
+        .map_err(|e| JsValue::from(e.to_string()))
+}
+
+/// Replaces the stored model with a safetensors file, e.g. one written by the
+/// `train` binary. Refused if it was made for a different board.
+#[wasm_bindgen]
+pub async fn import_model(bytes: Vec<u8>) -> Result<(), JsValue> {
+    let mut model =
+        model::Model::from_safetensors(&bytes).map_err(|e| JsValue::from(e.to_string()))?;
+    let current = config::current();
+    model.config().check(&current)?;
+    model.set_train_config(current.train);
+    write_model(model)
+        .await
+        .map_err(|e| JsValue::from(format!("{:?}", e)))
+}
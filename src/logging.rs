@@ -0,0 +1,15 @@
+/// Logs to the browser console in wasm and to stderr everywhere else,
+/// since calling into `web_sys::console` off-wasm panics
+pub fn log(msg: &str) {
+    #[cfg(target_arch = "wasm32")]
+    web_sys::console::log_1(&msg.into());
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("{}", msg);
+}
+
+pub fn error(msg: &str) {
+    #[cfg(target_arch = "wasm32")]
+    web_sys::console::error_1(&msg.into());
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("error: {}", msg);
+}
@@ -1,207 +1,1257 @@
 use crate::{
-    consts::{HIDDEN, QUADRANTS, RESOLUTION},
-    state::{Distribution, Image, Sequence},
+    config::{Algorithm, Config, ConvLayer, Layer, TrainConfig},
+    logging,
+    observation::Frame,
+    optim::{Optimizer, OptimizerSerializer},
+    returns::{discounted_returns, explained_variance, normalize},
+    rng::randn,
+    state::{Distribution, Sequence},
 };
 
-use candle_core::{DType, Device, Tensor};
-use candle_nn::ops::softmax;
+use candle_core::{DType, Device, Tensor, Var};
+use candle_nn::{
+    conv2d, linear,
+    ops::{leaky_relu, log_softmax, sigmoid, softmax},
+    Conv2d, Conv2dConfig, Linear, Module, VarBuilder, VarMap,
+};
+use rand::Rng;
+use safetensors::SafeTensors;
 use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, fs, io, path::Path};
 use wasm_bindgen::prelude::*;
-use web_sys::js_sys::{Object, Reflect};
+use web_sys::js_sys::Object;
+
+/// UP, DOWN and STAY
+pub(crate) const ACTIONS: usize = 3;
+/// Bumped whenever `ModelSerializer` changes, see `ModelSerializer::migrate`
+pub const MODEL_VERSION: u32 = 1;
+/// Keeps the log of a move's stored probability finite when it was 0
+const MIN_PROBABILITY: f32 = 1e-8;
+/// Safetensors metadata key holding the model's `Config` as JSON
+const SAFETENSORS_CONFIG: &str = "config";
+const SAFETENSORS_ID: &str = "id";
+/// Prefix of the value head's variables, beside the policy's "l1", "l2", ...
+const VALUE: &str = "value";
+/// How much of a negative input `Activation::LeakyRelu` lets through
+const LEAKY_RELU_SLOPE: f64 = 0.01;
+
+/// What a hidden layer applies to its output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Activation {
+    #[default]
+    Relu,
+    LeakyRelu,
+    Tanh,
+    Sigmoid,
+}
+
+impl Activation {
+    fn apply(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Activation::Relu => xs.relu(),
+            Activation::LeakyRelu => leaky_relu(xs, LEAKY_RELU_SLOPE),
+            Activation::Tanh => xs.tanh(),
+            Activation::Sigmoid => sigmoid(xs),
+        }
+    }
+}
+
+/// How the weights of a new model are drawn, from a normal distribution with
+/// a standard deviation set by each layer's fan in and fan out. Biases start at 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum Init {
+    /// N(0, 1), which saturates the softmax with hundreds of inputs
+    Normal,
+    /// Xavier/Glorot: std = sqrt(2 / (fan_in + fan_out))
+    #[default]
+    Xavier,
+    /// He: std = sqrt(2 / fan_in), made for ReLU layers
+    He,
+    /// std = gain / sqrt(fan_in), e.g. 1 for LeCun's init
+    ScaledNormal(f32),
+}
+
+impl Init {
+    fn std(&self, fan_in: usize, fan_out: usize) -> f32 {
+        match self {
+            Init::Normal => 1.0,
+            Init::Xavier => (2.0 / (fan_in + fan_out) as f32).sqrt(),
+            Init::He => (2.0 / fan_in as f32).sqrt(),
+            Init::ScaledNormal(gain) => gain / (fan_in as f32).sqrt(),
+        }
+    }
+}
 
-#[derive(Clone, Debug)]
 pub struct Model {
     id: u8,
-    w1: Tensor,
-    w2: Tensor,
+    /// The weights and biases of every layer, named like `candle_nn` names them:
+    /// "l1.weight" is (width, input) and "l1.bias" is (width) for the first one
+    vars: VarMap,
     val: bool,
+    /// The board and network shape the model was made for
+    config: Config,
+    optimizer: Optimizer,
+}
+
+/// Why a stored model couldn't be loaded
+#[derive(Debug)]
+pub enum ModelError {
+    /// The record isn't a model at all
+    Decode(String),
+    UnsupportedVersion(u32),
+    /// A tensor holds a different number of values than its shape needs
+    Length {
+        tensor: String,
+        shape: Vec<usize>,
+        len: usize,
+    },
+    /// A layer doesn't fit the input or the layer before it
+    Shape {
+        tensor: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    /// The output layer is missing, or isn't the last layer
+    Architecture(String),
+    /// The agent can't be stored this way
+    Unsupported(String),
+    /// The model was made for a different board, see `Config::check`
+    Incompatible(String),
+    Tensor(candle_core::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModelError::Decode(e) => write!(f, "invalid model record: {}", e),
+            ModelError::UnsupportedVersion(v) => {
+                write!(f, "model version {} is newer than {}", v, MODEL_VERSION)
+            }
+            ModelError::Length { tensor, shape, len } => write!(
+                f,
+                "{} should have shape {:?} but holds {} values",
+                tensor, shape, len
+            ),
+            ModelError::Shape {
+                tensor,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} should have shape {:?}, not {:?}",
+                tensor, expected, found
+            ),
+            ModelError::Architecture(e) => write!(f, "invalid model architecture: {}", e),
+            ModelError::Unsupported(e) => write!(f, "{}", e),
+            ModelError::Incompatible(e) => write!(f, "{}", e),
+            ModelError::Tensor(e) => write!(f, "{}", e),
+            ModelError::Io(e) => write!(f, "model i/o failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+impl From<candle_core::Error> for ModelError {
+    fn from(e: candle_core::Error) -> Self {
+        ModelError::Tensor(e)
+    }
+}
+
+impl From<io::Error> for ModelError {
+    fn from(e: io::Error) -> Self {
+        ModelError::Io(e)
+    }
 }
 
+impl From<safetensors::SafeTensorError> for ModelError {
+    fn from(e: safetensors::SafeTensorError) -> Self {
+        ModelError::Decode(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ModelError {
+    fn from(e: serde_json::Error) -> Self {
+        ModelError::Decode(e.to_string())
+    }
+}
+
+impl From<serde_wasm_bindgen::Error> for ModelError {
+    fn from(e: serde_wasm_bindgen::Error) -> Self {
+        ModelError::Decode(e.to_string())
+    }
+}
+
+/// A stored model.
+///
+/// Versions:
+/// - 0: `w1` (input, hidden) and `w2` (hidden, 3), no biases and no `version`
+/// - 1: `layers`, each with its shape, weights and bias
 #[derive(Deserialize, Serialize)]
 pub struct ModelSerializer {
+    /// Missing from records before version 1
+    #[serde(default)]
+    version: u32,
     id: u8,
+    val: bool,
+    #[serde(default)]
+    config: Config,
+    /// Conv layers from the input side, before `layers`
+    #[serde(default)]
+    conv: Vec<ConvSerializer>,
+    /// Every layer from the input side, the output layer last
+    #[serde(default)]
+    layers: Vec<LayerSerializer>,
+    /// The value head, for models trained as an actor-critic
+    #[serde(default)]
+    value: Option<LayerSerializer>,
+    /// Version 0 weights, moved into `layers` by `migrate`
+    #[serde(default, skip_serializing)]
     w1: Vec<f32>,
+    #[serde(default, skip_serializing)]
     w2: Vec<f32>,
-    val: bool,
+    /// Stored with the weights so training picks up where it left off
+    #[serde(default)]
+    optimizer: OptimizerSerializer,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LayerSerializer {
+    input: usize,
+    output: usize,
+    /// None for the output layer
+    activation: Option<Activation>,
+    /// (output, input), row by row like `candle_nn::Linear`
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+}
+
+impl LayerSerializer {
+    /// The layer's (weight, bias)
+    fn into_tensors(self) -> Result<(Tensor, Tensor), candle_core::Error> {
+        let device = Device::Cpu;
+        Ok((
+            Tensor::from_vec(self.weight, (self.output, self.input), &device)?,
+            Tensor::from_vec(self.bias, self.output, &device)?,
+        ))
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ConvSerializer {
+    /// Input channels
+    input: usize,
+    /// Output channels
+    output: usize,
+    kernel: usize,
+    stride: usize,
+    padding: usize,
+    activation: Activation,
+    /// (output, input, kernel, kernel) like `candle_nn::Conv2d`
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+}
+
+impl ConvSerializer {
+    fn layer(&self) -> ConvLayer {
+        ConvLayer {
+            channels: self.output,
+            kernel: self.kernel,
+            stride: self.stride,
+            padding: self.padding,
+            activation: self.activation,
+        }
+    }
+
+    /// The layer's (weight, bias)
+    fn into_tensors(self) -> Result<(Tensor, Tensor), candle_core::Error> {
+        let device = Device::Cpu;
+        let shape = (self.output, self.input, self.kernel, self.kernel);
+        Ok((
+            Tensor::from_vec(self.weight, shape, &device)?,
+            Tensor::from_vec(self.bias, self.output, &device)?,
+        ))
+    }
+}
+
+impl ModelSerializer {
+    /// Brings a record of any older version up to MODEL_VERSION
+    pub fn migrate(mut self) -> Result<ModelSerializer, ModelError> {
+        if self.version == 0 {
+            // the two-layer ReLU network stored (in, out) without biases
+            let input = self.config.input_size();
+            let hidden = self
+                .config
+                .model
+                .hidden
+                .first()
+                .map_or(0, |layer| layer.width);
+            let (w1, w2) = (std::mem::take(&mut self.w1), std::mem::take(&mut self.w2));
+            for (i, (w, (rows, cols))) in vec![(w1, (input, hidden)), (w2, (hidden, ACTIONS))]
+                .into_iter()
+                .enumerate()
+            {
+                let tensor = format!("w{}", i + 1);
+                check_len(&tensor, &[rows, cols], w.len())?;
+                let weight = Tensor::from_vec(w, (rows, cols), &Device::Cpu)?
+                    .t()?
+                    .flatten_all()?
+                    .to_vec1()?;
+                self.layers.push(LayerSerializer {
+                    input: rows,
+                    output: cols,
+                    activation: if i == 0 { Some(Activation::Relu) } else { None },
+                    weight,
+                    bias: vec![0.0; cols],
+                });
+            }
+            self.version = 1;
+        }
+        if self.version > MODEL_VERSION {
+            return Err(ModelError::UnsupportedVersion(self.version));
+        }
+        Ok(self)
+    }
+}
+
+fn check_len(tensor: &str, shape: &[usize], len: usize) -> Result<(), ModelError> {
+    if shape.iter().product::<usize>() != len {
+        return Err(ModelError::Length {
+            tensor: tensor.to_string(),
+            shape: shape.to_vec(),
+            len,
+        });
+    }
+    Ok(())
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Inference {
+    /// What the move was drawn from, exploration included
     pub dist: Distribution,
     pub choice: u8,
-    pub hidden: String,
+    /// The entropy of the policy before exploration was mixed in, or None if
+    /// the agent doesn't have one apart from `dist`
+    #[serde(default)]
+    pub entropy: Option<f32>,
+    /// The policy's probability of `choice` before exploration was mixed in,
+    /// or None like `entropy`
+    #[serde(default)]
+    pub probability: Option<f32>,
+}
+
+impl Inference {
+    /// The entropy of the policy, which unlike the entropy of `dist` can fall
+    /// all the way to 0 however much exploration is mixed in
+    pub fn policy_entropy(&self) -> f32 {
+        self.entropy.unwrap_or_else(|| self.dist.entropy())
+    }
+
+    /// The policy's probability of `choice`, what PPO measures how far an
+    /// update moved the policy against
+    pub fn policy_probability(&self) -> f32 {
+        self.probability
+            .unwrap_or_else(|| self.dist.probability(self.choice))
+    }
+}
+
+/// How one call to `Agent::train` went
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrainStats {
+    /// Frames in the batch
+    pub frames: usize,
+    /// Mean return per frame, after normalizing if the config does
+    pub mean_return: f32,
+    /// -log P(choice) weighted by the return or advantage, summed over frames
+    pub policy_loss: Option<f32>,
+    /// With DQN, the mean squared error of the Q-values against their targets
+    pub q_loss: Option<f32>,
+    /// The policy's mean entropy over the batch, in nats
+    pub entropy: Option<f32>,
+    /// Mean squared error of the value head's estimates, if there is one
+    pub value_loss: Option<f32>,
+    /// How much of the returns' variance the value head accounts for
+    pub explained_variance: Option<f32>,
+    /// With PPO, the share of frames whose probability ratio was clipped,
+    /// averaged over the epochs
+    pub clip_fraction: Option<f32>,
+    /// With PPO, an estimate of the KL divergence of the updated policy from
+    /// the one that played, averaged over the epochs
+    pub kl: Option<f32>,
+}
+
+impl fmt::Display for TrainStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} frames, mean return {:.3}",
+            self.frames, self.mean_return
+        )?;
+        if let Some(policy_loss) = self.policy_loss {
+            write!(f, ", policy loss {:.3}", policy_loss)?;
+        }
+        if let Some(q_loss) = self.q_loss {
+            write!(f, ", Q loss {:.4}", q_loss)?;
+        }
+        if let Some(entropy) = self.entropy {
+            write!(f, ", entropy {:.3}", entropy)?;
+        }
+        if let (Some(value_loss), Some(explained)) = (self.value_loss, self.explained_variance) {
+            write!(
+                f,
+                ", value loss {:.3}, explained variance {:.3}",
+                value_loss, explained
+            )?;
+        }
+        if let (Some(clip_fraction), Some(kl)) = (self.clip_fraction, self.kl) {
+            write!(f, ", clip fraction {:.3}, KL {:.4}", clip_fraction, kl)?;
+        }
+        Ok(())
+    }
+}
+
+/// The layers of the policy network, each with the activation after it, and
+/// the value head if the model has one
+struct Policy {
+    /// (channels, side) of the board image, if the network starts with conv layers
+    image: Option<(usize, usize)>,
+    convs: Vec<(Conv2d, Activation)>,
+    layers: Vec<(Linear, Option<Activation>)>,
+    value: Option<Linear>,
+}
+
+impl Policy {
+    /// The logits of UP, DOWN and STAY for each row of `xs`, and the value
+    /// head's estimate of each row's return. The value head reads the same
+    /// features as the output layer.
+    fn forward_with_value(&self, xs: &Tensor) -> candle_core::Result<(Tensor, Option<Tensor>)> {
+        let mut xs = match self.image {
+            Some((channels, dim)) => {
+                let mut xs = xs.reshape((xs.dim(0)?, channels, dim, dim))?;
+                for (conv, activation) in &self.convs {
+                    xs = activation.apply(&conv.forward(&xs)?)?;
+                }
+                xs.flatten_from(1)?
+            }
+            None => xs.clone(),
+        };
+        let mut value = None;
+        for (i, (layer, activation)) in self.layers.iter().enumerate() {
+            if i + 1 == self.layers.len() {
+                value = self.value.as_ref().map(|v| v.forward(&xs)).transpose()?;
+            }
+            xs = layer.forward(&xs)?;
+            if let Some(activation) = activation {
+                xs = activation.apply(&xs)?;
+            }
+        }
+        Ok((xs, value))
+    }
+}
+
+impl Module for Policy {
+    /// The logits of UP, DOWN and STAY for each row of `xs`
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        Ok(self.forward_with_value(xs)?.0)
+    }
+}
+
+/// (input, output, activation) of every layer the config describes, the
+/// output layer last
+fn architecture(config: &Config) -> Vec<(usize, usize, Option<Activation>)> {
+    let mut input = config.dense_input_size();
+    let mut layers = Vec::with_capacity(config.model.hidden.len() + 1);
+    for layer in &config.model.hidden {
+        layers.push((input, layer.width, Some(layer.activation)));
+        input = layer.width;
+    }
+    layers.push((input, ACTIONS, None));
+    layers
+}
+
+/// The input width of the value head, if the config has one
+fn value_input(config: &Config) -> Option<usize> {
+    config.model.value_head.then(|| {
+        config
+            .model
+            .hidden
+            .last()
+            .map_or(config.dense_input_size(), |layer| layer.width)
+    })
+}
+
+/// Input channels and the layer of every conv layer the config describes
+fn conv_architecture(config: &Config) -> Vec<(usize, ConvLayer)> {
+    let mut input = config.input_channels();
+    config
+        .model
+        .conv
+        .iter()
+        .map(|conv| {
+            let layer = (input, *conv);
+            input = conv.channels;
+            layer
+        })
+        .collect()
+}
+
+fn layer_name(index: usize) -> String {
+    format!("l{}", index + 1)
+}
+
+fn conv_name(index: usize) -> String {
+    format!("c{}", index + 1)
+}
+
+/// A clone gets its own copy of the weights, so training one model doesn't
+/// move the other, e.g. the opponent it was cloned into
+impl Clone for Model {
+    fn clone(&self) -> Self {
+        let vars = VarMap::new();
+        for (name, var) in self.vars.data().lock().unwrap().iter() {
+            let copy = var
+                .as_tensor()
+                .copy()
+                .and_then(|t| Var::from_tensor(&t))
+                .unwrap_throw();
+            vars.data().lock().unwrap().insert(name.clone(), copy);
+        }
+        Model {
+            id: self.id,
+            vars,
+            val: self.val,
+            config: self.config.clone(),
+            optimizer: self.optimizer.clone(),
+        }
+    }
 }
 
 /// The implementation of the model.
-/// The model is a simple neural network with two layers.
+/// The model is a multilayer perceptron, shaped by `ModelConfig::hidden`,
+/// optionally after conv layers over the board (`ModelConfig::conv`).
 ///
 /// RL model:
-/// - input: QUADRANTS x QUADRANTS matrix
+/// - input: the downsampled board built from recent frames, or the current
+///   frame's features, as the ObservationMode says
 /// - output: P(UP), P(DOWN), P(STAY)
-/// - loss: cross-entropy
+/// - loss: -log P(choice) * return, backpropagated by candle
 ///
-/// The model is trained using Policy Gradient method.
+/// The model is trained using Policy Gradient method, or as an advantage
+/// actor-critic when `ModelConfig::value_head` adds a value estimate
+/// (https://arxiv.org/abs/1602.01783). Either can take PPO's clipped steps
+/// instead, see `Algorithm`.
 impl Model {
-    pub fn new() -> Model {
-        let device = Device::Cpu;
-        Model {
-            id: 0,
-            val: false,
-            w1: Tensor::randn(
-                0f32,
-                1.0,
-                ((QUADRANTS / RESOLUTION) * (QUADRANTS / RESOLUTION), HIDDEN),
-                &device,
+    pub fn new<R: Rng + ?Sized>(config: Config, rng: &mut R) -> Model {
+        let mut new_wrapper = || -> Result<Model, candle_core::Error> {
+            let convs = conv_architecture(&config)
+                .into_iter()
+                .map(|(input, conv)| {
+                    let area = conv.kernel * conv.kernel;
+                    let std = config.model.init.std(input * area, conv.channels * area);
+                    let weight = randn(rng, 0f32, std, (conv.channels, input * area))?
+                        .reshape((conv.channels, input, conv.kernel, conv.kernel))?;
+                    Ok((
+                        weight,
+                        Tensor::zeros(conv.channels, DType::F32, &Device::Cpu)?,
+                    ))
+                })
+                .collect::<Result<_, candle_core::Error>>()?;
+            let mut layer = |input: usize, output: usize| -> Result<_, candle_core::Error> {
+                // drawn (input, output) so seeded weights match the old layout
+                let std = config.model.init.std(input, output);
+                let weight = randn(rng, 0f32, std, (input, output))?.t()?.contiguous()?;
+                Ok((weight, Tensor::zeros(output, DType::F32, &Device::Cpu)?))
+            };
+            let layers = architecture(&config)
+                .into_iter()
+                .map(|(input, output, _)| layer(input, output))
+                .collect::<Result<_, _>>()?;
+            // drawn after the policy, which starts the same with or without it
+            let value = value_input(&config)
+                .map(|input| layer(input, 1))
+                .transpose()?;
+            Model::from_layers(
+                0,
+                false,
+                config.clone(),
+                convs,
+                layers,
+                value,
+                Optimizer::new(),
             )
-            .unwrap_throw(),
-            w2: Tensor::randn(0f32, 1.0, (HIDDEN, 3), &device).unwrap_throw(),
-        }
+        };
+        new_wrapper().unwrap_throw()
     }
 
-    pub fn from_jsobject(model: JsValue) -> Result<Model, serde_wasm_bindgen::Error> {
-        let device = Device::Cpu;
-        let model: ModelSerializer = serde_wasm_bindgen::from_value(model)?;
+    /// Builds a model from the (weight, bias) of every conv and fully connected
+    /// layer in `config`, and of its value head if it has one
+    fn from_layers(
+        id: u8,
+        val: bool,
+        config: Config,
+        convs: Vec<(Tensor, Tensor)>,
+        layers: Vec<(Tensor, Tensor)>,
+        value: Option<(Tensor, Tensor)>,
+        optimizer: Optimizer,
+    ) -> Result<Model, candle_core::Error> {
+        let vars = VarMap::new();
+        let named = convs
+            .into_iter()
+            .enumerate()
+            .map(|(i, conv)| (conv_name(i), conv))
+            .chain(
+                layers
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, layer)| (layer_name(i), layer)),
+            )
+            .chain(value.map(|layer| (VALUE.to_string(), layer)));
+        for (name, (weight, bias)) in named {
+            let mut data = vars.data().lock().unwrap();
+            data.insert(format!("{}.weight", name), Var::from_tensor(&weight)?);
+            data.insert(format!("{}.bias", name), Var::from_tensor(&bias)?);
+        }
         Ok(Model {
-            id: model.id,
-            val: model.val,
-            w1: Tensor::from_vec(model.w1, (QUADRANTS, HIDDEN), &device).unwrap_or_else(|e| {
-                web_sys::console::error_1(&e.to_string().into());
-                Tensor::randn(0f32, 1.0, (QUADRANTS, HIDDEN), &device).unwrap_throw()
-            }),
-            w2: Tensor::from_vec(model.w2, (HIDDEN, 3), &device).unwrap_or_else(|e| {
-                web_sys::console::error_1(&e.to_string().into());
-                Tensor::randn(0f32, 1.0, (HIDDEN, 3), &device).unwrap_throw()
-            }),
+            id,
+            vars,
+            val,
+            config,
+            optimizer,
+        })
+    }
+
+    fn var(&self, name: &str) -> Result<Tensor, candle_core::Error> {
+        self.vars
+            .data()
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|var| var.as_tensor().clone())
+            .ok_or_else(|| candle_core::Error::Msg(format!("missing variable {}", name)))
+    }
+
+    fn policy(&self) -> Result<Policy, candle_core::Error> {
+        let vb = VarBuilder::from_varmap(&self.vars, DType::F32, &Device::Cpu);
+        let convs = conv_architecture(&self.config)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (input, conv))| {
+                let config = Conv2dConfig {
+                    padding: conv.padding,
+                    stride: conv.stride.max(1),
+                    ..Default::default()
+                };
+                let layer = conv2d(
+                    input,
+                    conv.channels,
+                    conv.kernel,
+                    config,
+                    vb.pp(conv_name(i)),
+                )?;
+                Ok((layer, conv.activation))
+            })
+            .collect::<Result<_, candle_core::Error>>()?;
+        let layers = architecture(&self.config)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (input, output, activation))| {
+                Ok((linear(input, output, vb.pp(layer_name(i)))?, activation))
+            })
+            .collect::<Result<_, candle_core::Error>>()?;
+        let value = value_input(&self.config)
+            .map(|input| linear(input, 1, vb.pp(VALUE)))
+            .transpose()?;
+        let image = (!self.config.model.conv.is_empty())
+            .then(|| (self.config.input_channels(), self.config.frame_dim()));
+        Ok(Policy {
+            image,
+            convs,
+            layers,
+            value,
+        })
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Swaps the training hyperparameters, which don't affect the weights' shape
+    pub fn set_train_config(&mut self, train: TrainConfig) {
+        self.config.train = train;
+    }
+
+    fn serialize_layer(
+        &self,
+        name: &str,
+        (input, output, activation): (usize, usize, Option<Activation>),
+    ) -> Result<LayerSerializer, candle_core::Error> {
+        Ok(LayerSerializer {
+            input,
+            output,
+            activation,
+            weight: self
+                .var(&format!("{}.weight", name))?
+                .flatten_all()?
+                .to_vec1()?,
+            bias: self.var(&format!("{}.bias", name))?.to_vec1()?,
         })
     }
 
-    // the proper way to do this is the same deep js object creation we do for model...
-    // but, I'm lazy
-    pub fn serialize_hidden(tensor: &Tensor) -> Result<String, candle_core::Error> {
-        Ok(tensor
-            .flatten_all()?
-            .to_vec1::<f32>()?
+    pub fn serialize(&self) -> Result<ModelSerializer, candle_core::Error> {
+        let conv = conv_architecture(&self.config)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (input, conv))| {
+                Ok(ConvSerializer {
+                    input,
+                    output: conv.channels,
+                    kernel: conv.kernel,
+                    stride: conv.stride,
+                    padding: conv.padding,
+                    activation: conv.activation,
+                    weight: self
+                        .var(&format!("{}.weight", conv_name(i)))?
+                        .flatten_all()?
+                        .to_vec1()?,
+                    bias: self.var(&format!("{}.bias", conv_name(i)))?.to_vec1()?,
+                })
+            })
+            .collect::<Result<_, candle_core::Error>>()?;
+        let layers = architecture(&self.config)
+            .into_iter()
+            .enumerate()
+            .map(|(i, layer)| self.serialize_layer(&layer_name(i), layer))
+            .collect::<Result<_, candle_core::Error>>()?;
+        let value = value_input(&self.config)
+            .map(|input| self.serialize_layer(VALUE, (input, 1, None)))
+            .transpose()?;
+        Ok(ModelSerializer {
+            version: MODEL_VERSION,
+            id: self.id,
+            val: self.val,
+            config: self.config.clone(),
+            conv,
+            layers,
+            value,
+            w1: Vec::new(),
+            w2: Vec::new(),
+            optimizer: self.optimizer.serialize()?,
+        })
+    }
+
+    /// Rebuilds a stored model, migrating it first if it's from an older
+    /// version. Every layer's shape is checked against the input and the layer
+    /// before it, and against the values it holds.
+    pub fn deserialize(model: ModelSerializer) -> Result<Model, ModelError> {
+        let model = model.migrate()?;
+        model.config.validate().map_err(ModelError::Architecture)?;
+        let mut config = model.config;
+        let (output, hidden) = model
+            .layers
+            .split_last()
+            .ok_or_else(|| ModelError::Architecture("no layers".to_string()))?;
+        if output.activation.is_some() || hidden.iter().any(|l| l.activation.is_none()) {
+            return Err(ModelError::Architecture(
+                "only the output layer goes without an activation".to_string(),
+            ));
+        }
+
+        let mut channels = config.input_channels();
+        let mut dim = config.frame_dim();
+        for (i, conv) in model.conv.iter().enumerate() {
+            let weight = format!("{}.weight", conv_name(i));
+            let shape = vec![conv.output, conv.input, conv.kernel, conv.kernel];
+            if conv.input != channels {
+                return Err(ModelError::Shape {
+                    tensor: weight,
+                    expected: vec![conv.output, channels, conv.kernel, conv.kernel],
+                    found: shape,
+                });
+            }
+            check_len(&weight, &shape, conv.weight.len())?;
+            check_len(
+                &format!("{}.bias", conv_name(i)),
+                &[conv.output],
+                conv.bias.len(),
+            )?;
+            dim = conv.layer().output_dim(dim);
+            if dim == 0 {
+                return Err(ModelError::Architecture(format!(
+                    "{} leaves nothing of the board",
+                    conv_name(i)
+                )));
+            }
+            channels = conv.output;
+        }
+        config.model.conv = model.conv.iter().map(ConvSerializer::layer).collect();
+
+        let mut input = config.dense_input_size();
+        for (i, layer) in model.layers.iter().enumerate() {
+            let weight = format!("{}.weight", layer_name(i));
+            let output = if i + 1 == model.layers.len() {
+                ACTIONS
+            } else {
+                layer.output
+            };
+            if (layer.input, layer.output) != (input, output) {
+                return Err(ModelError::Shape {
+                    tensor: weight,
+                    expected: vec![output, input],
+                    found: vec![layer.output, layer.input],
+                });
+            }
+            check_len(&weight, &[layer.output, layer.input], layer.weight.len())?;
+            check_len(
+                &format!("{}.bias", layer_name(i)),
+                &[layer.output],
+                layer.bias.len(),
+            )?;
+            input = layer.output;
+        }
+
+        if let Some(value) = &model.value {
+            let weight = format!("{}.weight", VALUE);
+            if value.activation.is_some() {
+                return Err(ModelError::Architecture(
+                    "the value head goes without an activation".to_string(),
+                ));
+            }
+            if (value.input, value.output) != (output.input, 1) {
+                return Err(ModelError::Shape {
+                    tensor: weight,
+                    expected: vec![1, output.input],
+                    found: vec![value.output, value.input],
+                });
+            }
+            check_len(&weight, &[value.output, value.input], value.weight.len())?;
+            check_len(&format!("{}.bias", VALUE), &[1], value.bias.len())?;
+        }
+
+        config.model.hidden = hidden
             .iter()
-            .map(|x| format!("{}", x))
-            .collect::<Vec<_>>()
-            .join(","))
+            .map(|l| Layer {
+                width: l.output,
+                activation: l.activation.unwrap_or_default(),
+            })
+            .collect();
+        config.model.value_head = model.value.is_some();
+        let convs = model
+            .conv
+            .into_iter()
+            .map(ConvSerializer::into_tensors)
+            .collect::<Result<_, candle_core::Error>>()?;
+        let layers = model
+            .layers
+            .into_iter()
+            .map(LayerSerializer::into_tensors)
+            .collect::<Result<_, candle_core::Error>>()?;
+        let value = model.value.map(LayerSerializer::into_tensors).transpose()?;
+        Ok(Model::from_layers(
+            model.id,
+            model.val,
+            config,
+            convs,
+            layers,
+            value,
+            Optimizer::deserialize(model.optimizer)?,
+        )?)
     }
 
-    pub fn deserialize_hidden(hidden: &str) -> Result<Tensor, candle_core::Error> {
-        let hidden = hidden
-            .split(",")
-            .map(|x| x.parse::<f32>().unwrap_throw())
+    pub fn from_jsobject(model: JsValue) -> Result<Model, ModelError> {
+        Model::deserialize(serde_wasm_bindgen::from_value(model)?)
+    }
+
+    /// The weights in the safetensors format, named as `candle_nn` names them
+    /// ("c1.weight" for conv layers, "l1.weight", "l1.bias", ..., "value.weight"
+    /// with a value head) with the config in the metadata. The
+    /// optimizer state isn't included, so training starts it over.
+    pub fn to_safetensors(&self) -> Result<Vec<u8>, ModelError> {
+        let tensors = self
+            .vars
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
             .collect::<Vec<_>>();
-        Ok(Tensor::from_vec(hidden, (1, HIDDEN), &Device::Cpu)?)
+        let metadata = HashMap::from([
+            (
+                SAFETENSORS_CONFIG.to_string(),
+                serde_json::to_string(&self.config)?,
+            ),
+            (SAFETENSORS_ID.to_string(), self.id.to_string()),
+        ]);
+        Ok(safetensors::serialize(tensors, Some(metadata))?)
     }
 
-    pub fn to_jsobject(&self) -> Result<Object, JsValue> {
-        let to_jsobject_wrapper = || -> Result<(Vec<f32>, Vec<f32>), candle_core::Error> {
-            let w1: Vec<f32> = self.w1.to_vec2()?.into_iter().flatten().collect();
-            let w2: Vec<f32> = self.w2.to_vec2()?.into_iter().flatten().collect();
-            Ok((w1, w2))
+    /// Rebuilds a model from `to_safetensors`, checking every layer's shape
+    /// like `deserialize` does
+    pub fn from_safetensors(bytes: &[u8]) -> Result<Model, ModelError> {
+        let (_, header) = SafeTensors::read_metadata(bytes)?;
+        let metadata = header.metadata().clone().unwrap_or_default();
+        let config: Config = serde_json::from_str(
+            metadata
+                .get(SAFETENSORS_CONFIG)
+                .ok_or_else(|| ModelError::Decode("no config in the metadata".to_string()))?,
+        )?;
+        // the config decides which tensors are read, so check it first
+        config.validate().map_err(ModelError::Architecture)?;
+        let id = metadata
+            .get(SAFETENSORS_ID)
+            .and_then(|id| id.parse().ok())
+            .unwrap_or(0);
+
+        let tensors = candle_core::safetensors::load_buffer(bytes, &Device::Cpu)?;
+        let tensor = |name: String| -> Result<Tensor, ModelError> {
+            let tensor = tensors
+                .get(&name)
+                .ok_or_else(|| ModelError::Architecture(format!("no {} tensor", name)))?;
+            Ok(tensor.to_dtype(DType::F32)?)
         };
-        let (w1, w2) = to_jsobject_wrapper().unwrap_or_else(|e| {
-            web_sys::console::error_1(&e.to_string().into());
-            (Vec::new(), Vec::new())
-        });
-        let object = Object::new();
-        Reflect::set(&object, &"id".into(), &JsValue::from(self.id))?;
-        Reflect::set(&object, &"w1".into(), &JsValue::from(w1))?;
-        Reflect::set(&object, &"w2".into(), &JsValue::from(w2))?;
-        Reflect::set(&object, &"val".into(), &JsValue::from(self.val))?;
-        Ok(object)
+        let layer = |name: String, activation| -> Result<LayerSerializer, ModelError> {
+            let weight = tensor(format!("{}.weight", name))?;
+            let (output, input) = weight.dims2()?;
+            Ok(LayerSerializer {
+                input,
+                output,
+                activation,
+                weight: weight.flatten_all()?.to_vec1()?,
+                bias: tensor(format!("{}.bias", name))?.flatten_all()?.to_vec1()?,
+            })
+        };
+        let conv = config
+            .model
+            .conv
+            .iter()
+            .enumerate()
+            .map(|(i, conv)| {
+                let weight = tensor(format!("{}.weight", conv_name(i)))?;
+                let (output, input, kernel, _) = weight.dims4()?;
+                Ok(ConvSerializer {
+                    input,
+                    output,
+                    kernel,
+                    stride: conv.stride,
+                    padding: conv.padding,
+                    activation: conv.activation,
+                    weight: weight.flatten_all()?.to_vec1()?,
+                    bias: tensor(format!("{}.bias", conv_name(i)))?
+                        .flatten_all()?
+                        .to_vec1()?,
+                })
+            })
+            .collect::<Result<_, ModelError>>()?;
+        let layers = architecture(&config)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (_, _, activation))| layer(layer_name(i), activation))
+            .collect::<Result<_, ModelError>>()?;
+        let value = config
+            .model
+            .value_head
+            .then(|| layer(VALUE.to_string(), None))
+            .transpose()?;
+        Model::deserialize(ModelSerializer {
+            version: MODEL_VERSION,
+            id,
+            val: false,
+            config,
+            conv,
+            layers,
+            value,
+            w1: Vec::new(),
+            w2: Vec::new(),
+            optimizer: OptimizerSerializer::default(),
+        })
+    }
+
+    pub fn save_safetensors<P: AsRef<Path>>(&self, path: P) -> Result<(), ModelError> {
+        Ok(fs::write(path, self.to_safetensors()?)?)
+    }
+
+    pub fn load_safetensors<P: AsRef<Path>>(path: P) -> Result<Model, ModelError> {
+        Model::from_safetensors(&fs::read(path)?)
+    }
+
+    pub fn to_jsobject(&self) -> Result<Object, JsValue> {
+        let model = self.serialize().map_err(|e| JsValue::from(e.to_string()))?;
+        Ok(serde_wasm_bindgen::to_value(&model)?.unchecked_into())
+    }
+
+    /// Builds the network input from a player's recent frames
+    pub fn observe(&self, frames: &[Frame]) -> Vec<f32> {
+        self.config
+            .model
+            .observation
+            .observe(frames, self.config.frame_size())
+    }
+
+    /// How many recent frames `infer` wants to see
+    pub fn history(&self) -> usize {
+        self.config.model.observation.frames()
+    }
+
+    /// The output layer's values for each row of `inputs`, before the softmax.
+    /// The DQN agent reads them as each move's Q-value.
+    pub(crate) fn forward(&self, inputs: &Tensor) -> Result<Tensor, candle_core::Error> {
+        self.policy()?.forward(inputs)
+    }
+
+    /// Backpropagates `loss` and lets the optimizer update the weights
+    pub(crate) fn step(&mut self, loss: &Tensor) -> Result<(), candle_core::Error> {
+        let grads = loss.backward()?;
+        let train = self.config.train;
+        self.optimizer.update(&train, &self.vars, &grads)
     }
 
     // https://karpathy.github.io/2016/05/31/rl/
-    pub fn infer(&self, img: Image) -> Inference {
-        let infer_wrapper = || -> Result<Inference, candle_core::Error> {
+    /// Picks a move from the frames a player has seen this game, oldest first
+    /// and ending with the current one. The policy is tempered and mixed with
+    /// random moves as `TrainConfig::exploration` says at this point in
+    /// training, and the result is what the move is drawn from and stored.
+    pub fn infer<R: Rng + ?Sized>(&self, frames: &[Frame], rng: &mut R) -> Inference {
+        let mut infer_wrapper = || -> Result<Inference, candle_core::Error> {
             let input = Tensor::from_vec(
-                img,
-                (1, (QUADRANTS / RESOLUTION) * (QUADRANTS / RESOLUTION)),
+                self.observe(frames),
+                (1, self.config.input_size()),
                 &Device::Cpu,
-            )?
-            .to_dtype(DType::F32)?;
-            let h1 = input.matmul(&self.w1)?.relu()?;
-            let h2 = h1.matmul(&self.w2)?;
-            let p = softmax(&h2, 1)?.flatten_all()?.to_vec1::<f32>()?;
-            let dist = Distribution::new(p[0], p[1], p[2]);
-            let choice = dist.sample();
+            )?;
+            let logits = self.policy()?.forward(&input)?;
+            let p = softmax(&logits, 1)?.flatten_all()?.to_vec1::<f32>()?;
+            let exploration = self.config.train.exploration;
+            let step = self.optimizer.step();
+            let policy = Distribution::new(p[0], p[1], p[2]);
+            let dist = policy
+                .with_temperature(exploration.temperature.at(step))
+                .with_epsilon(exploration.epsilon.at(step));
+            let choice = dist.sample(rng);
             Ok(Inference {
                 dist,
                 choice,
-                hidden: Model::serialize_hidden(&h1)?,
+                entropy: Some(policy.entropy()),
+                probability: Some(policy.probability(choice)),
             })
         };
         infer_wrapper().unwrap_or_else(|e| {
-            web_sys::console::error_1(&e.to_string().into());
+            logging::error(&e.to_string());
             Inference {
                 dist: Distribution::new(0.0, 0.0, 0.0),
                 choice: 0,
-                hidden: String::new(),
+                entropy: None,
+                probability: None,
             }
         })
     }
 
-    pub fn train(&mut self, seq: &Sequence) {
-        // grab all the states
-        // create the rewards for each of the states
-        // - was it a win or loss
-        // - how far in the cycle did it happen (discounted reward)
-        // modulate the gradients based on the discounted rewards (multiply)
-        // run backpropagation with the hidden states and the modulated gradients
-        // update the weights
-        // repeat until all states are trained on
-        let mut train_wrapper = || -> Result<(), candle_core::Error> {
-            let mut rewards = vec![0.0; seq.len()];
-            for i in (0..seq.len()).rev() {
-                let mut discounted_reward;
-                if seq.get_outcome().unwrap_throw() {
-                    discounted_reward = 1.0;
-                } else {
-                    discounted_reward = 0.0;
+    /// Trains on a batch of finished games, with the gradients of every frame
+    /// in the batch summed together into each update. That's one update with
+    /// `Algorithm::Reinforce` and one per epoch with `Algorithm::Ppo`. Returns
+    /// how it went, or None if there was nothing to train on.
+    pub fn train(&mut self, batch: &[Sequence]) -> Option<TrainStats> {
+        // grab all the states of every finished game
+        // reward them with the scheme stored with each game
+        // discount it back through the earlier frames, so actions closer to the
+        // point count for more, and optionally standardize the returns
+        // run the whole batch back through the network, one row per frame
+        // the loss is -log P(choice) weighted by the return, summed over frames
+        // with a value head, weight it by the advantage (the return less the
+        // value head's estimate) instead, and add the value head's squared error
+        // PPO weights the ratio of P(choice) to the probability it was played
+        // with instead, clipped so one batch can't move the policy too far
+        // subtract the policy's entropy, so it doesn't settle on a move early
+        // let candle backpropagate it and the optimizer update the weights
+        let batch = batch
+            .iter()
+            .filter(|seq| seq.is_finished())
+            .collect::<Vec<_>>();
+        let mut train_wrapper = || -> Result<Option<TrainStats>, candle_core::Error> {
+            let train = self.config.train;
+            let mut returns = batch
+                .iter()
+                .flat_map(|seq| discounted_returns(&seq.rewards(), train.gamma))
+                .collect::<Vec<_>>();
+            if returns.is_empty() {
+                return Ok(None);
+            }
+            if train.normalize {
+                normalize(&mut returns);
+            }
+            let n = returns.len();
+            let mean_return = returns.iter().sum::<f32>() / n as f32;
+
+            let frames = batch.iter().flat_map(|seq| {
+                seq.get_sequence()
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, state)| (seq, i, state))
+            });
+            let mut inputs = Vec::with_capacity(n * self.config.input_size());
+            let mut choices = Vec::with_capacity(n);
+            let mut old_log_probs = Vec::with_capacity(n);
+            for (seq, i, state) in frames {
+                let inference = state.to_tuple().1;
+                inputs.extend(self.observe(&seq.frames_at(i, self.history())));
+                choices.push(inference.choice as u32);
+                old_log_probs.push(inference.policy_probability().max(MIN_PROBABILITY).ln());
+            }
+            let device = Device::Cpu;
+            let inputs = Tensor::from_vec(inputs, (n, self.config.input_size()), &device)?;
+            let choices = Tensor::from_vec(choices, (n, 1), &device)?;
+            let targets = Tensor::from_vec(returns.clone(), (n, 1), &device)?;
+            let old_log_probs = Tensor::from_vec(old_log_probs, (n, 1), &device)?;
+
+            let epochs = match train.algorithm {
+                Algorithm::Reinforce => 1,
+                Algorithm::Ppo { epochs, .. } => epochs.max(1),
+            };
+            let mut stats = TrainStats {
+                frames: n,
+                mean_return,
+                ..TrainStats::default()
+            };
+            let mut advantages = None;
+            for epoch in 0..epochs {
+                let (logits, values) = self.policy()?.forward_with_value(&inputs)?;
+                let all_log_probs = log_softmax(&logits, 1)?;
+                let log_probs = all_log_probs.gather(&choices, 1)?;
+                let entropy = all_log_probs.exp()?.mul(&all_log_probs)?.sum_all()?.neg()?;
+                // the value head learns from its own loss, not through the
+                // advantage, which stays as the first epoch estimated it
+                let advantages = match (&advantages, &values) {
+                    (Some(advantages), _) => advantages,
+                    (None, Some(values)) => advantages.insert(targets.sub(&values.detach())?),
+                    (None, None) => advantages.insert(targets.clone()),
+                };
+                let policy_loss = match train.algorithm {
+                    Algorithm::Reinforce => log_probs.mul(advantages)?.sum_all()?.neg()?,
+                    Algorithm::Ppo { clip, .. } => {
+                        let ratios = log_probs.sub(&old_log_probs)?.exp()?;
+                        let clipped = ratios.clamp(1.0 - clip, 1.0 + clip)?;
+                        let clipped_frames = ratios
+                            .flatten_all()?
+                            .to_vec1::<f32>()?
+                            .iter()
+                            .filter(|r| (*r - 1.0).abs() > clip)
+                            .count();
+                        let kl = old_log_probs.sub(&log_probs)?.mean_all()?;
+                        let epochs = epochs as f32;
+                        *stats.clip_fraction.get_or_insert(0.0) +=
+                            clipped_frames as f32 / n as f32 / epochs;
+                        *stats.kl.get_or_insert(0.0) += kl.to_scalar::<f32>()? / epochs;
+                        ratios
+                            .mul(advantages)?
+                            .minimum(&clipped.mul(advantages)?)?
+                            .sum_all()?
+                            .neg()?
+                    }
+                };
+                let loss = (&policy_loss - entropy.affine(train.entropy_coef as f64, 0.0)?)?;
+                let loss = match &values {
+                    Some(values) => {
+                        let value_loss = values.sub(&targets)?.sqr()?.sum_all()?;
+                        if epoch == 0 {
+                            let estimates = values.flatten_all()?.to_vec1::<f32>()?;
+                            stats.value_loss = Some(value_loss.to_scalar::<f32>()? / n as f32);
+                            stats.explained_variance =
+                                Some(explained_variance(&returns, &estimates));
+                        }
+                        (loss + value_loss.affine(train.value_coef as f64, 0.0)?)?
+                    }
+                    None => loss,
+                };
+                if epoch == 0 {
+                    stats.policy_loss = Some(policy_loss.to_scalar()?);
+                    stats.entropy = Some(entropy.to_scalar::<f32>()? / n as f32);
                 }
-                discounted_reward = 0.99 * discounted_reward;
-                rewards[i] = discounted_reward;
+                self.step(&loss)?;
             }
-            web_sys::console::log_1(
-                &(rewards
-                    .clone()
-                    .into_iter()
-                    .reduce(|acc, i| (acc + i))
-                    .unwrap()
-                    / rewards.len() as f32)
-                    .to_string()
-                    .into(),
-            );
-            for i in 0..seq.len() {
-                let state = &seq.get_sequence()[i];
-                let reward = rewards[i];
-                let (image, inference) = state.to_tuple();
-                let choice = inference.choice;
-                let hidden = Model::deserialize_hidden(&inference.hidden)?;
-                let dist = inference.dist.to_vec();
-                let mut d_h2 = [0.0; 3];
-                d_h2[0] = (dist[0] - if choice == 0 { 1.0 } else { 0.0 }) * reward;
-                d_h2[1] = (dist[1] - if choice == 1 { 1.0 } else { 0.0 }) * reward;
-                d_h2[2] = (dist[2] - if choice == 2 { 1.0 } else { 0.0 }) * reward;
-                let d_h2 = Tensor::from_vec(d_h2.to_vec(), (1, 3), &Device::Cpu)?;
-                let d_w2 = hidden.t()?.matmul(&d_h2)?;
-                let d_h1 = d_h2.matmul(&self.w2.t()?)?;
-                let d_w1 = Tensor::from_vec(
-                    image.to_vec(),
-                    (1, (QUADRANTS / RESOLUTION) * (QUADRANTS / RESOLUTION)),
-                    &Device::Cpu,
-                )?
-                .to_dtype(DType::F32)?
-                .t()?
-                .matmul(&d_h1)?;
-                self.w1 = self.w1.sub(&d_w1)?;
-                self.w2 = self.w2.sub(&d_w2)?;
-            }
-            Ok(())
+            Ok(Some(stats))
         };
 
-        train_wrapper().unwrap_or_else(|e| web_sys::console::error_1(&e.to_string().into()));
+        let stats = train_wrapper().unwrap_or_else(|e| {
+            logging::error(&e.to_string());
+            None
+        });
+        if let Some(stats) = &stats {
+            logging::log(&stats.to_string());
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng;
+
+    /// A 2x2 board and 2 hidden units, small enough to write weights out by hand
+    fn small_config() -> Config {
+        let mut config = Config::default();
+        config.model.resolution = config.game.quadrants / 2;
+        config.model.hidden = vec![Layer {
+            width: 2,
+            activation: Activation::Relu,
+        }];
+        config
+    }
+
+    fn weight(model: &Model, name: &str) -> Vec<Vec<f32>> {
+        let vars = model.vars.data().lock().unwrap();
+        vars[name].as_tensor().to_vec2().unwrap()
+    }
+
+    #[test]
+    fn version_0_weights_are_transposed() {
+        let config = small_config();
+        let input = config.input_size();
+        let w1 = (0..input * 2).map(|i| i as f32).collect::<Vec<_>>();
+        let w2 = (0..2 * ACTIONS).map(|i| i as f32).collect::<Vec<_>>();
+        let record: ModelSerializer = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "val": false,
+            "config": config,
+            "w1": w1,
+            "w2": w2,
+        }))
+        .unwrap();
+        let model = Model::deserialize(record).unwrap();
+        // w1 was (input, hidden) and l1.weight is (hidden, input)
+        let l1 = weight(&model, "l1.weight");
+        let l2 = weight(&model, "l2.weight");
+        for j in 0..2 {
+            for i in 0..input {
+                assert_eq!(l1[j][i], w1[i * 2 + j]);
+            }
+            for k in 0..ACTIONS {
+                assert_eq!(l2[k][j], w2[j * ACTIONS + k]);
+            }
+        }
+    }
+
+    #[test]
+    fn layer_length_mismatch_is_an_error() {
+        let model = Model::new(small_config(), &mut rng::seeded(0));
+        let mut record = model.serialize().unwrap();
+        record.layers[0].weight.pop();
+        assert!(matches!(
+            Model::deserialize(record),
+            Err(ModelError::Length { .. })
+        ));
+    }
+
+    #[test]
+    fn newer_version_is_refused() {
+        let model = Model::new(small_config(), &mut rng::seeded(0));
+        let mut record = model.serialize().unwrap();
+        record.version = MODEL_VERSION + 1;
+        assert!(matches!(
+            Model::deserialize(record),
+            Err(ModelError::UnsupportedVersion(v)) if v == MODEL_VERSION + 1
+        ));
     }
 }
@@ -0,0 +1,198 @@
+use crate::{
+    config::GameConfig,
+    engine::{GameState, Player},
+    raster::to_image,
+    state::Image,
+};
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// How frames are turned into the input of the network
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ObservationMode {
+    /// The current frame as is
+    #[default]
+    Frame,
+    /// The current frame minus the previous one, so the ball's direction shows up
+    /// (https://karpathy.github.io/2016/05/31/rl/)
+    Difference,
+    /// The last K frames side by side, oldest first
+    Stack(usize),
+    /// The current frame's `Features` instead of its pixels
+    Features,
+}
+
+impl ObservationMode {
+    /// How many frames, including the current one, the observation is built from
+    pub fn frames(&self) -> usize {
+        match self {
+            ObservationMode::Frame | ObservationMode::Features => 1,
+            ObservationMode::Difference => 2,
+            ObservationMode::Stack(k) => *k,
+        }
+    }
+
+    /// Whether the input is the board's pixels, which conv layers need
+    pub fn is_image(&self) -> bool {
+        *self != ObservationMode::Features
+    }
+
+    /// Size of the network input for frames of `frame_size` pixels
+    pub fn input_size(&self, frame_size: usize) -> usize {
+        match self {
+            ObservationMode::Frame | ObservationMode::Difference => frame_size,
+            ObservationMode::Stack(k) => k * frame_size,
+            ObservationMode::Features => FEATURES,
+        }
+    }
+
+    /// Builds the network input from the frames seen so far, oldest first.
+    /// Missing frames at the start of a game count as empty, and so do
+    /// missing features, e.g. in games stored before they were recorded.
+    pub fn observe(&self, frames: &[Frame], frame_size: usize) -> Vec<f32> {
+        let current = frames.last();
+        let pixel = |frame: Option<&Frame>, i: usize| frame.map_or(0.0, |f| f.img[i] as f32);
+        match self {
+            ObservationMode::Frame => (0..frame_size).map(|i| pixel(current, i)).collect(),
+            ObservationMode::Difference => {
+                let previous = frames.len().checked_sub(2).map(|i| &frames[i]);
+                (0..frame_size)
+                    .map(|i| pixel(current, i) - pixel(previous, i))
+                    .collect()
+            }
+            ObservationMode::Stack(k) => {
+                let missing = k.saturating_sub(frames.len());
+                let kept = &frames[frames.len().saturating_sub(*k)..];
+                let padding = std::iter::repeat_n(None, missing);
+                padding
+                    .chain(kept.iter().map(Some))
+                    .flat_map(|frame| (0..frame_size).map(move |i| pixel(frame, i)))
+                    .collect()
+            }
+            ObservationMode::Features => current
+                .and_then(|frame| frame.features)
+                .map_or(vec![0.0; FEATURES], |features| features.to_vec()),
+        }
+    }
+}
+
+impl FromStr for ObservationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ObservationMode, String> {
+        match s {
+            "frame" => Ok(ObservationMode::Frame),
+            "difference" => Ok(ObservationMode::Difference),
+            "features" => Ok(ObservationMode::Features),
+            _ => match s.strip_prefix("stack:").map(str::parse) {
+                Some(Ok(k)) if k > 0 => Ok(ObservationMode::Stack(k)),
+                _ => Err(format!("unknown observation mode {}", s)),
+            },
+        }
+    }
+}
+
+/// Size of `Features::to_vec`
+pub const FEATURES: usize = 6;
+
+/// Where the ball and paddles are and where the ball is going, each scaled to
+/// [0, 1]. They're seen from the player's own side, so the ball's x is 0 at
+/// the player's paddle and 1 at the opponent's whichever player it is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Features {
+    pub ball_x: f32,
+    pub ball_y: f32,
+    /// 0.5 when the ball isn't moving along x, higher when it's heading for
+    /// the opponent
+    pub ball_dx: f32,
+    /// 0.5 when the ball isn't moving along y, higher when it's heading down
+    pub ball_dy: f32,
+    /// The top of the player's paddle, 0 at the top of the board and 1 as low
+    /// as it goes
+    pub paddle_y: f32,
+    pub opponent_y: f32,
+}
+
+impl Features {
+    /// Scales raw positions for `player`. They're in board cells and from
+    /// player one's side, as `GameState::positions` and `getPositions` in
+    /// `index.js` give them: the ball's x, y, dx and dy, then the top of
+    /// player one's paddle and of player two's.
+    pub fn from_positions(positions: [f64; 6], player: Player, game: &GameConfig) -> Features {
+        let [x, y, dx, dy, p1, p2] = positions;
+        let side = game.quadrants as f64;
+        let (x, dx, paddle, opponent) = match player {
+            Player::One => (x, dx, p1, p2),
+            Player::Two => (side - x, -dx, p2, p1),
+        };
+        // the ball keeps its speed along x, along y it's fastest coming off
+        // the end of a paddle or on the serve
+        let max_dx = game.ball_dx.abs();
+        let max_dy = (game.paddle_height / 8.0).max(game.ball_dy.abs() / 2.0);
+        let position = |v: f64, max: f64| unit(v / max);
+        let velocity = |v: f64, max: f64| unit((v / max + 1.0) / 2.0);
+        let paddle_range = side - game.paddle_height;
+        Features {
+            ball_x: position(x, side),
+            ball_y: position(y, side),
+            ball_dx: velocity(dx, max_dx),
+            ball_dy: velocity(dy, max_dy),
+            paddle_y: position(paddle, paddle_range),
+            opponent_y: position(opponent, paddle_range),
+        }
+    }
+
+    pub fn from_game(game: &GameState, player: Player) -> Features {
+        Features::from_positions(game.positions(), player, &game.config)
+    }
+
+    pub fn to_vec(&self) -> Vec<f32> {
+        vec![
+            self.ball_x,
+            self.ball_y,
+            self.ball_dx,
+            self.ball_dy,
+            self.paddle_y,
+            self.opponent_y,
+        ]
+    }
+}
+
+/// Clamps to [0, 1], with anything undefined (like a 0 / 0) as 0.5
+fn unit(v: f64) -> f32 {
+    if v.is_nan() {
+        return 0.5;
+    }
+    v.clamp(0.0, 1.0) as f32
+}
+
+/// What a player sees on one tick: the downsampled board, and the game's
+/// features when whoever supplied the board had them
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Frame {
+    pub img: Image,
+    #[serde(default)]
+    pub features: Option<Features>,
+}
+
+impl Frame {
+    pub fn new(img: Image, features: Option<Features>) -> Frame {
+        Frame { img, features }
+    }
+
+    /// The frame `player` sees of a headless game, like the browser's
+    pub fn from_game(game: &GameState, player: Player, resolution: usize) -> Frame {
+        Frame {
+            img: to_image(game, player, resolution),
+            features: Some(Features::from_game(game, player)),
+        }
+    }
+}
+
+/// Appends a frame to a history, only keeping the last `keep` of them
+pub fn push_frame<T>(frames: &mut Vec<T>, frame: T, keep: usize) {
+    frames.push(frame);
+    let excess = frames.len().saturating_sub(keep);
+    frames.drain(..excess);
+}
@@ -0,0 +1,189 @@
+use crate::config::TrainConfig;
+
+use candle_core::{backprop::GradStore, Device, Tensor};
+use candle_nn::VarMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Keeps the RMSProp and Adam updates finite when a gradient has always been zero
+const EPSILON: f64 = 1e-8;
+
+/// How gradients turn into weight updates
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum OptimizerKind {
+    /// Plain gradient descent with a running velocity, no momentum at 0
+    Sgd { momentum: f32 },
+    /// Scales each weight's step by a running average of its squared gradient,
+    /// as in https://karpathy.github.io/2016/05/31/rl/
+    RmsProp { decay: f32 },
+    /// RMSProp with momentum and bias correction
+    Adam { beta1: f32, beta2: f32 },
+}
+
+impl Default for OptimizerKind {
+    fn default() -> Self {
+        OptimizerKind::RmsProp { decay: 0.99 }
+    }
+}
+
+impl OptimizerKind {
+    fn name(&self) -> &'static str {
+        match self {
+            OptimizerKind::Sgd { .. } => "sgd",
+            OptimizerKind::RmsProp { .. } => "rmsprop",
+            OptimizerKind::Adam { .. } => "adam",
+        }
+    }
+
+    /// How many running averages are kept per parameter
+    fn slots(&self) -> usize {
+        match self {
+            OptimizerKind::Sgd { .. } | OptimizerKind::RmsProp { .. } => 1,
+            OptimizerKind::Adam { .. } => 2,
+        }
+    }
+}
+
+/// Multiplies the learning rate by `rate` every `every` updates
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Decay {
+    pub rate: f32,
+    pub every: u64,
+}
+
+/// The running averages an optimizer keeps per parameter, keyed by name
+#[derive(Clone, Debug, Default)]
+pub struct Optimizer {
+    /// The kind the slots were built for, `None` until the first update
+    kind: Option<String>,
+    /// Updates applied so far, for the decay schedule and Adam's bias correction
+    step: u64,
+    slots: BTreeMap<String, Vec<Tensor>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SlotSerializer {
+    shape: Vec<usize>,
+    values: Vec<Vec<f32>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct OptimizerSerializer {
+    kind: Option<String>,
+    step: u64,
+    slots: BTreeMap<String, SlotSerializer>,
+}
+
+impl Optimizer {
+    pub fn new() -> Optimizer {
+        Optimizer::default()
+    }
+
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    /// The learning rate the next update will use
+    pub fn learning_rate(&self, train: &TrainConfig) -> f32 {
+        match train.decay {
+            Some(Decay { rate, every }) if every > 0 => {
+                train.learning_rate * rate.powi((self.step / every) as i32)
+            }
+            _ => train.learning_rate,
+        }
+    }
+
+    /// Moves every variable against its gradient in `grads` as one update
+    pub fn update(
+        &mut self,
+        train: &TrainConfig,
+        vars: &VarMap,
+        grads: &GradStore,
+    ) -> Result<(), candle_core::Error> {
+        let kind = train.optimizer;
+        // the running averages of one optimizer mean nothing to another
+        if self.kind.as_deref() != Some(kind.name()) {
+            self.kind = Some(kind.name().to_string());
+            self.slots.clear();
+        }
+        let lr = self.learning_rate(train) as f64;
+        self.step += 1;
+        let t = self.step as i32;
+
+        let vars = vars.data().lock().unwrap();
+        for (name, var) in vars.iter() {
+            let grad = match grads.get(var.as_tensor()) {
+                Some(grad) => grad,
+                None => continue,
+            };
+            let slots = self.slots.entry(name.to_string()).or_default();
+            if slots.len() != kind.slots() || slots.iter().any(|s| s.dims() != grad.dims()) {
+                *slots = (0..kind.slots())
+                    .map(|_| grad.zeros_like())
+                    .collect::<Result<_, _>>()?;
+            }
+            let step = match kind {
+                OptimizerKind::Sgd { momentum } => {
+                    // v = momentum * v + grad
+                    slots[0] = (slots[0].affine(momentum as f64, 0.0)? + grad)?;
+                    slots[0].affine(lr, 0.0)?
+                }
+                OptimizerKind::RmsProp { decay } => {
+                    // cache = decay * cache + (1 - decay) * grad^2
+                    let decay = decay as f64;
+                    slots[0] =
+                        (slots[0].affine(decay, 0.0)? + grad.sqr()?.affine(1.0 - decay, 0.0)?)?;
+                    (grad.affine(lr, 0.0)? / slots[0].sqrt()?.affine(1.0, EPSILON)?)?
+                }
+                OptimizerKind::Adam { beta1, beta2 } => {
+                    let (beta1, beta2) = (beta1 as f64, beta2 as f64);
+                    slots[0] = (slots[0].affine(beta1, 0.0)? + grad.affine(1.0 - beta1, 0.0)?)?;
+                    slots[1] =
+                        (slots[1].affine(beta2, 0.0)? + grad.sqr()?.affine(1.0 - beta2, 0.0)?)?;
+                    // both averages start at zero, so scale them up early on
+                    let m = slots[0].affine(1.0 / (1.0 - beta1.powi(t)), 0.0)?;
+                    let v = slots[1].affine(1.0 / (1.0 - beta2.powi(t)), 0.0)?;
+                    (m.affine(lr, 0.0)? / v.sqrt()?.affine(1.0, EPSILON)?)?
+                }
+            };
+            var.set(&var.as_tensor().sub(&step)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn serialize(&self) -> Result<OptimizerSerializer, candle_core::Error> {
+        let mut slots = BTreeMap::new();
+        for (name, tensors) in &self.slots {
+            let shape = tensors.first().map_or(Vec::new(), |t| t.dims().to_vec());
+            let values = tensors
+                .iter()
+                .map(|t| t.flatten_all()?.to_vec1::<f32>())
+                .collect::<Result<_, _>>()?;
+            slots.insert(name.clone(), SlotSerializer { shape, values });
+        }
+        Ok(OptimizerSerializer {
+            kind: self.kind.clone(),
+            step: self.step,
+            slots,
+        })
+    }
+
+    pub fn deserialize(optimizer: OptimizerSerializer) -> Result<Optimizer, candle_core::Error> {
+        let mut slots = BTreeMap::new();
+        for (name, slot) in optimizer.slots {
+            let shape = slot.shape;
+            let tensors = slot
+                .values
+                .into_iter()
+                .map(|values| Tensor::from_vec(values, shape.as_slice(), &Device::Cpu))
+                .collect::<Result<_, _>>()?;
+            slots.insert(name, tensors);
+        }
+        Ok(Optimizer {
+            kind: optimizer.kind,
+            step: optimizer.step,
+            slots,
+        })
+    }
+}
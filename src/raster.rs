@@ -0,0 +1,65 @@
+use crate::{
+    engine::{GameState, Player},
+    state::Image,
+};
+
+/// The full resolution board, indexed `[x][y]` like `getGameBoard` in `index.js`
+pub type Board = Vec<Vec<bool>>;
+
+/// Marks every cell in the inclusive rectangle, clipped to the board
+fn fill(board: &mut Board, x0: i64, x1: i64, y0: i64, y1: i64) {
+    let max = board.len() as i64 - 1;
+    for i in x0.max(0)..=x1.min(max) {
+        for j in y0.max(0)..=y1.min(max) {
+            board[i as usize][j as usize] = true;
+        }
+    }
+}
+
+/// Port of `getGameBoard`: a `quadrants` x `quadrants` grid with the paddles and ball set
+pub fn game_board(state: &GameState) -> Board {
+    let quadrants = state.config.quadrants;
+    let mut board = vec![vec![false; quadrants]; quadrants];
+    for paddle in [&state.p1, &state.p2] {
+        let x = paddle.x.floor() as i64;
+        let y = paddle.y.floor() as i64;
+        let w = paddle.w.floor() as i64;
+        let h = paddle.h.floor() as i64;
+        fill(&mut board, x, x + w, y, y + h);
+    }
+    let x = state.ball.x.floor() as i64;
+    let y = state.ball.y.floor() as i64;
+    let size = state.ball.size.floor() as i64;
+    fill(&mut board, x - size, x + size, y - size, y + size);
+    board
+}
+
+/// Port of `decrease_resolution` in `worker.js`: a pooled cell is 1 if any of
+/// the `factor` x `factor` cells under it are set
+pub fn decrease_resolution(board: &Board, factor: usize) -> Vec<Vec<u8>> {
+    let new_dim = board.len() / factor;
+    (0..new_dim)
+        .map(|i| {
+            (0..new_dim)
+                .map(|j| {
+                    let any = (0..factor)
+                        .any(|k| (0..factor).any(|l| board[i * factor + k][j * factor + l]));
+                    any as u8
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Builds the image a player's model sees, the same way `send_state` does,
+/// pooling the board by `resolution`.
+///
+/// Player two gets every row reversed like `row.slice().reverse()`. Rows are
+/// board columns, so this flips the view along y rather than x.
+pub fn to_image(state: &GameState, player: Player, resolution: usize) -> Image {
+    let mut grid = decrease_resolution(&game_board(state), resolution);
+    if player == Player::Two {
+        grid.iter_mut().for_each(|row| row.reverse());
+    }
+    grid.into_iter().flatten().collect()
+}
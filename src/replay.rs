@@ -0,0 +1,255 @@
+use crate::{
+    config::Config,
+    state::{Distribution, Image, Sequence},
+};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+use wasm_bindgen::prelude::*;
+
+/// Replay files start with this so we don't try to read anything else
+pub const REPLAY_MAGIC: &[u8; 8] = b"PONGRPLY";
+/// Bumped whenever the layout below changes
+pub const REPLAY_VERSION: u16 = 1;
+/// Limits on what a replay says it holds, so a corrupt file can't ask for more
+/// memory than a real game would ever need
+const MAX_HEADER_LEN: usize = 1 << 20;
+const MAX_FRAME_SIZE: usize = 1 << 20;
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    Header(serde_json::Error),
+    NotAReplay,
+    UnsupportedVersion(u16),
+    /// The file is a replay but what it says doesn't add up
+    Corrupt(String),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "replay i/o failed: {}", e),
+            ReplayError::Header(e) => write!(f, "invalid replay header: {}", e),
+            ReplayError::NotAReplay => write!(f, "not a replay file"),
+            ReplayError::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "replay version {} isn't one of 1 to {}",
+                    v, REPLAY_VERSION
+                )
+            }
+            ReplayError::Corrupt(e) => write!(f, "corrupt replay: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<io::Error> for ReplayError {
+    fn from(e: io::Error) -> Self {
+        ReplayError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ReplayError {
+    fn from(e: serde_json::Error) -> Self {
+        ReplayError::Header(e)
+    }
+}
+
+/// Everything about a replay except the frames
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReplayHeader {
+    /// The board and model shape the game was played with
+    pub config: Config,
+    pub model_id: u8,
+    /// The seed of the run, if it was seeded
+    pub seed: Option<u64>,
+    /// When the game started and ended, in ms since the epoch
+    pub started: Option<f64>,
+    pub ended: Option<f64>,
+    /// Whether player one won the point
+    pub outcome: Option<bool>,
+}
+
+/// What player one saw and did for a single frame
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReplayFrame {
+    pub img: Image,
+    pub choice: u8,
+    pub dist: Distribution,
+}
+
+/// A recorded game.
+///
+/// On disk (all integers little endian):
+/// - REPLAY_MAGIC, then REPLAY_VERSION as a u16
+/// - the header as JSON, prefixed with its length as a u32
+/// - the number of frames as a u32
+/// - per frame: the image packed 8 pixels to a byte, the choice as a u8, and
+///   P(UP), P(DOWN), P(STAY) as f32s
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub frames: Vec<ReplayFrame>,
+}
+
+fn packed_len(frame_size: usize) -> usize {
+    frame_size.div_ceil(8)
+}
+
+fn pack(img: &Image, frame_size: usize) -> Vec<u8> {
+    let mut packed = vec![0u8; packed_len(frame_size)];
+    img.iter()
+        .take(frame_size)
+        .enumerate()
+        .filter(|(_, &pixel)| pixel != 0)
+        .for_each(|(i, _)| packed[i / 8] |= 0x80 >> (i % 8));
+    packed
+}
+
+fn unpack(packed: &[u8], frame_size: usize) -> Image {
+    (0..frame_size)
+        .map(|i| (packed[i / 8] >> (7 - i % 8)) & 1)
+        .collect()
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_f32<R: Read>(reader: &mut R) -> io::Result<f32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+impl Replay {
+    /// Records the frames player one saw in a game
+    pub fn from_sequence(
+        seq: &Sequence,
+        config: Config,
+        model_id: u8,
+        seed: Option<u64>,
+    ) -> Replay {
+        let (started, ended) = seq.get_times();
+        Replay {
+            header: ReplayHeader {
+                config,
+                model_id,
+                seed,
+                started,
+                ended,
+                outcome: seq.get_outcome(),
+            },
+            frames: seq
+                .get_sequence()
+                .iter()
+                .map(|state| {
+                    let (img, inference) = state.to_tuple();
+                    ReplayFrame {
+                        img,
+                        choice: inference.choice,
+                        dist: inference.dist,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), ReplayError> {
+        let frame_size = self.header.config.frame_size();
+        let header = serde_json::to_vec(&self.header)?;
+        writer.write_all(REPLAY_MAGIC)?;
+        writer.write_all(&REPLAY_VERSION.to_le_bytes())?;
+        writer.write_all(&(header.len() as u32).to_le_bytes())?;
+        writer.write_all(&header)?;
+        writer.write_all(&(self.frames.len() as u32).to_le_bytes())?;
+        for frame in &self.frames {
+            writer.write_all(&pack(&frame.img, frame_size))?;
+            writer.write_all(&[frame.choice])?;
+            for p in frame.dist.to_vec() {
+                writer.write_all(&p.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Replay, ReplayError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != REPLAY_MAGIC {
+            return Err(ReplayError::NotAReplay);
+        }
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        // versions start at 1, a 0 was never written
+        if version == 0 || version > REPLAY_VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+
+        let header_len = read_u32(reader)? as usize;
+        if header_len > MAX_HEADER_LEN {
+            return Err(ReplayError::Corrupt(format!(
+                "a {} byte header",
+                header_len
+            )));
+        }
+        let mut header = vec![0u8; header_len];
+        reader.read_exact(&mut header)?;
+        let header: ReplayHeader = serde_json::from_slice(&header)?;
+
+        let config = &header.config;
+        if config.model.resolution == 0 {
+            return Err(ReplayError::Corrupt("a resolution of 0".to_string()));
+        }
+        let frame_dim = config.frame_dim();
+        if frame_dim == 0 || frame_dim.saturating_mul(frame_dim) > MAX_FRAME_SIZE {
+            return Err(ReplayError::Corrupt(format!(
+                "{}x{} frames",
+                frame_dim, frame_dim
+            )));
+        }
+        let frame_size = config.frame_size();
+        // grown as frames are read rather than trusting the count up front
+        let count = read_u32(reader)? as usize;
+        let mut frames = Vec::new();
+        let mut packed = vec![0u8; packed_len(frame_size)];
+        for _ in 0..count {
+            reader.read_exact(&mut packed)?;
+            let mut choice = [0u8; 1];
+            reader.read_exact(&mut choice)?;
+            let dist = Distribution::new(read_f32(reader)?, read_f32(reader)?, read_f32(reader)?);
+            frames.push(ReplayFrame {
+                img: unpack(&packed, frame_size),
+                choice: choice[0],
+                dist,
+            });
+        }
+        Ok(Replay { header, frames })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ReplayError> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Replay, ReplayError> {
+        Replay::read(&mut bytes)
+    }
+}
+
+/// Decodes a replay file into a plain JS object, to watch it in the browser
+#[wasm_bindgen]
+pub fn decode_replay(bytes: &[u8]) -> Result<JsValue, JsValue> {
+    let replay = Replay::from_bytes(bytes).map_err(|e| JsValue::from(e.to_string()))?;
+    Ok(serde_wasm_bindgen::to_value(&replay)?)
+}
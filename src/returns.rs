@@ -0,0 +1,104 @@
+/// Keeps the standardized returns finite when every return is the same
+const EPSILON: f32 = 1e-8;
+
+/// The discounted return from every frame: G_t = r_t + gamma * G_{t+1}
+pub fn discounted_returns(rewards: &[f32], gamma: f32) -> Vec<f32> {
+    let mut returns = vec![0.0; rewards.len()];
+    let mut running = 0.0;
+    for (i, reward) in rewards.iter().enumerate().rev() {
+        running = reward + gamma * running;
+        returns[i] = running;
+    }
+    returns
+}
+
+fn mean_and_variance(xs: &[f32]) -> (f32, f32) {
+    let n = xs.len() as f32;
+    let mean = xs.iter().sum::<f32>() / n;
+    let var = xs.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / n;
+    (mean, var)
+}
+
+/// Standardizes returns in place to zero mean and unit standard deviation
+pub fn normalize(returns: &mut [f32]) {
+    if returns.is_empty() {
+        return;
+    }
+    let (mean, var) = mean_and_variance(returns);
+    let std = var.sqrt() + EPSILON;
+    returns.iter_mut().for_each(|r| *r = (*r - mean) / std);
+}
+
+/// How much of the returns' variance a value estimate accounts for:
+/// 1 - Var(G - V) / Var(G). 1 is a perfect estimate, 0 is no better than
+/// guessing the mean and below 0 is worse.
+pub fn explained_variance(returns: &[f32], values: &[f32]) -> f32 {
+    let (_, var) = mean_and_variance(returns);
+    let errors = returns
+        .iter()
+        .zip(values)
+        .map(|(r, v)| r - v)
+        .collect::<Vec<_>>();
+    let (_, error_var) = mean_and_variance(&errors);
+    1.0 - error_var / (var + EPSILON)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn reward_at_the_end_is_discounted_back() {
+        // 1, then 0.5 * 1, then 0.5 * 0.5 * 1
+        let returns = discounted_returns(&[0.0, 0.0, 1.0], 0.5);
+        assert_close(&returns, &[0.25, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn rewards_along_the_way_accumulate() {
+        // G2 = -1, G1 = 0 + 0.9 * -1 = -0.9, G0 = 1 + 0.9 * -0.9 = 0.19
+        let returns = discounted_returns(&[1.0, 0.0, -1.0], 0.9);
+        assert_close(&returns, &[0.19, -0.9, -1.0]);
+    }
+
+    #[test]
+    fn no_discount_sums_the_rest_of_the_game() {
+        let returns = discounted_returns(&[1.0, 2.0, 3.0], 1.0);
+        assert_close(&returns, &[6.0, 5.0, 3.0]);
+    }
+
+    #[test]
+    fn normalize_standardizes() {
+        // mean 2, population std sqrt(2/3)
+        let mut returns = vec![1.0, 2.0, 3.0];
+        normalize(&mut returns);
+        let z = 1.0 / (2.0f32 / 3.0).sqrt();
+        assert_close(&returns, &[-z, 0.0, z]);
+    }
+
+    #[test]
+    fn normalize_constant_returns_is_zero() {
+        let mut returns = vec![0.5; 4];
+        normalize(&mut returns);
+        assert_close(&returns, &[0.0; 4]);
+    }
+
+    #[test]
+    fn explained_variance_of_exact_values_is_one() {
+        let returns = [1.0, -1.0, 0.5];
+        assert_close(&[explained_variance(&returns, &returns)], &[1.0]);
+    }
+
+    #[test]
+    fn explained_variance_of_the_mean_is_zero() {
+        let returns = [1.0, 2.0, 3.0];
+        assert_close(&[explained_variance(&returns, &[2.0; 3])], &[0.0]);
+    }
+}
@@ -0,0 +1,31 @@
+use candle_core::{Device, Tensor};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::StandardNormal;
+
+/// The random source used for weight initialization and action sampling.
+///
+/// Anything taking `&mut R where R: Rng` works, this is just the one we
+/// hand out so a seed always reproduces the same run.
+pub type ModelRng = StdRng;
+
+pub fn seeded(seed: u64) -> ModelRng {
+    StdRng::seed_from_u64(seed)
+}
+
+/// An unseeded generator, for the browser where we don't care about replaying a run
+pub fn from_entropy() -> ModelRng {
+    StdRng::from_entropy()
+}
+
+/// Like `Tensor::randn`, but drawn from `rng` so it can be seeded
+pub fn randn<R: Rng + ?Sized>(
+    rng: &mut R,
+    mean: f32,
+    std: f32,
+    shape: (usize, usize),
+) -> Result<Tensor, candle_core::Error> {
+    let data = (0..shape.0 * shape.1)
+        .map(|_| mean + std * rng.sample::<f32, _>(StandardNormal))
+        .collect::<Vec<_>>();
+    Tensor::from_vec(data, shape, &Device::Cpu)
+}
@@ -1,13 +1,22 @@
 use crate::{
-    consts::{DB_NAME, MODEL_DB_KEY, MODEL_DB_KEY_VERSION, MODEL_STORE, STATE_DB_KEY, STATE_STORE},
-    model::{Inference, Model},
+    agent::Agent,
+    config::{self, AgentKind, Config, RewardScheme},
+    consts::{
+        DB_NAME, DQN_DB_KEY, MODEL_DB_KEY, MODEL_DB_KEY_VERSION, MODEL_STORE, REPLAY_DB_KEY,
+        STATE_DB_KEY, STATE_STORE,
+    },
+    dqn::{Dqn, ReplayBuffer},
+    model::{Inference, Model, ModelError},
+    observation::{push_frame, Features, Frame},
+    rng,
 };
 
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 use rexie::*;
 use wasm_bindgen::prelude::*;
-use web_sys::js_sys::Math::random;
 
 pub type Image = Vec<u8>;
 
@@ -24,8 +33,8 @@ impl Distribution {
         Distribution { up, down, stay }
     }
 
-    pub fn sample(&self) -> u8 {
-        let rand = random() as f32;
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> u8 {
+        let rand = rng.gen::<f32>();
         match rand {
             x if x < self.up => 0,
             x if x < self.up + self.down => 1,
@@ -46,18 +55,76 @@ impl Distribution {
     pub fn to_vec(&self) -> Vec<f32> {
         vec![self.up, self.down, self.stay]
     }
+
+    /// Divides the logits behind the distribution by `temperature`, by raising
+    /// every probability to 1 / `temperature` and renormalizing
+    pub fn with_temperature(&self, temperature: f32) -> Distribution {
+        if temperature <= 0.0 {
+            // the limit is picking the most likely move every time
+            let mut p = [0.0; 3];
+            p[self.choice() as usize] = 1.0;
+            return Distribution::new(p[0], p[1], p[2]);
+        }
+        let p = self
+            .to_vec()
+            .iter()
+            .map(|p| p.powf(1.0 / temperature))
+            .collect::<Vec<_>>();
+        let sum = p.iter().sum::<f32>();
+        if !sum.is_normal() {
+            return *self;
+        }
+        Distribution::new(p[0] / sum, p[1] / sum, p[2] / sum)
+    }
+
+    /// Mixes in a uniformly random move with chance `epsilon`
+    pub fn with_epsilon(&self, epsilon: f32) -> Distribution {
+        let epsilon = epsilon.clamp(0.0, 1.0);
+        let uniform = epsilon / 3.0;
+        Distribution::new(
+            (1.0 - epsilon) * self.up + uniform,
+            (1.0 - epsilon) * self.down + uniform,
+            (1.0 - epsilon) * self.stay + uniform,
+        )
+    }
+
+    /// How likely `choice` (0 UP, 1 DOWN, 2 STAY) was
+    pub fn probability(&self, choice: u8) -> f32 {
+        match choice {
+            0 => self.up,
+            1 => self.down,
+            _ => self.stay,
+        }
+    }
+
+    /// Entropy in nats, ln 3 when every move is as likely and 0 when one is certain
+    pub fn entropy(&self) -> f32 {
+        -self
+            .to_vec()
+            .iter()
+            .filter(|&&p| p > 0.0)
+            .map(|p| p * p.ln())
+            .sum::<f32>()
+    }
 }
 
 #[wasm_bindgen]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct State {
     img: Image,
+    /// For `ObservationMode::Features`, if the frame came with them
+    #[serde(default)]
+    features: Option<Features>,
     infer: Inference,
 }
 
 impl State {
-    pub fn new(img: Image, infer: Inference) -> State {
-        State { img, infer }
+    pub fn new(frame: Frame, infer: Inference) -> State {
+        State {
+            img: frame.img,
+            features: frame.features,
+            infer,
+        }
     }
     pub fn to_tuple(&self) -> (Image, Inference) {
         (self.img.clone(), self.infer.clone())
@@ -65,6 +132,9 @@ impl State {
     pub fn get_image(&self) -> &Image {
         &self.img
     }
+    pub fn get_features(&self) -> Option<Features> {
+        self.features
+    }
 }
 
 #[wasm_bindgen]
@@ -113,6 +183,33 @@ pub struct Sequence {
     outcome: Option<bool>,
     /// Lifecycle of the sequence <CURRENT, UNPROCESSED, PROCESSED>
     lifecycle: Lifecycle,
+    /// The last few frames each player saw, for observations built from several
+    /// frames.
+    #[serde(default)]
+    seen: Vec<Vec<Frame>>,
+    /// When the first frame was added and when the game ended, in ms since the epoch
+    #[serde(default)]
+    started: Option<f64>,
+    #[serde(default)]
+    ended: Option<f64>,
+    /// How the game is rewarded, fixed when it ends so training can be replayed
+    #[serde(default)]
+    rewards: Option<RewardScheme>,
+    /// The config and id of the agent that played the first frame, for replays
+    #[serde(default)]
+    config: Option<Config>,
+    #[serde(default)]
+    model_id: Option<u8>,
+}
+
+/// Milliseconds since the epoch, `SystemTime` panics in the browser
+pub fn now() -> f64 {
+    #[cfg(target_arch = "wasm32")]
+    return web_sys::js_sys::Date::now();
+    #[cfg(not(target_arch = "wasm32"))]
+    return std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_millis() as f64);
 }
 
 impl Sequence {
@@ -122,6 +219,12 @@ impl Sequence {
             sequence: Vec::new(),
             outcome: None,
             lifecycle: Lifecycle::new(),
+            seen: Vec::new(),
+            started: None,
+            ended: None,
+            rewards: None,
+            config: None,
+            model_id: None,
         }
     }
     pub fn new_with_id(id: f64) -> Sequence {
@@ -130,6 +233,12 @@ impl Sequence {
             sequence: Vec::new(),
             outcome: None,
             lifecycle: Lifecycle::new(),
+            seen: Vec::new(),
+            started: None,
+            ended: None,
+            rewards: None,
+            config: None,
+            model_id: None,
         }
     }
     pub fn get_outcome(&self) -> Option<bool> {
@@ -141,6 +250,80 @@ impl Sequence {
     pub fn get_sequence(&self) -> &Vec<State> {
         &self.sequence
     }
+    pub fn get_id(&self) -> f64 {
+        self.id
+    }
+    /// Remembers which agent is playing, unless one already is
+    pub fn record_agent(&mut self, config: &Config, model_id: u8) {
+        if self.model_id.is_none() {
+            self.config = Some(config.clone());
+            self.model_id = Some(model_id);
+        }
+    }
+    /// The config and id of the agent that played the game, if it was recorded
+    pub fn get_agent(&self) -> Option<(&Config, u8)> {
+        self.config.as_ref().zip(self.model_id)
+    }
+    /// When the game started and ended, if it has
+    pub fn get_times(&self) -> (Option<f64>, Option<f64>) {
+        (self.started, self.ended)
+    }
+    pub fn push(&mut self, state: State) {
+        self.started.get_or_insert_with(now);
+        self.sequence.push(state);
+    }
+    /// Records a frame a player saw and returns the last `count` frames they've
+    /// seen this game, oldest first
+    pub fn observe(&mut self, player: usize, frame: Frame, count: usize) -> Vec<Frame> {
+        if self.seen.len() <= player {
+            self.seen.resize(player + 1, Vec::new());
+        }
+        let frames = &mut self.seen[player];
+        push_frame(frames, frame, count);
+        frames.clone()
+    }
+    /// The last `count` stored frames up to and including the state at `index`
+    pub fn frames_at(&self, index: usize, count: usize) -> Vec<Frame> {
+        let start = (index + 1).saturating_sub(count);
+        self.sequence[start..=index]
+            .iter()
+            .map(|state| Frame::new(state.img.clone(), state.features))
+            .collect()
+    }
+    /// Whether the game has an outcome, games that never got one weren't
+    /// lost, just left unfinished
+    pub fn is_finished(&self) -> bool {
+        self.outcome.is_some()
+    }
+    /// The reward for every frame, from the outcome and the scheme the game
+    /// ended under (or the default one for games stored before schemes were).
+    /// Nothing for an unfinished game.
+    pub fn rewards(&self) -> Vec<f32> {
+        match self.outcome {
+            Some(outcome) => self
+                .rewards
+                .unwrap_or_default()
+                .rewards(self.len(), outcome),
+            None => Vec::new(),
+        }
+    }
+    /// The mean entropy of the policy over the game's moves, which falls
+    /// towards 0 as it collapses onto one move. See `Inference::policy_entropy`.
+    pub fn mean_entropy(&self) -> f32 {
+        let total = self
+            .sequence
+            .iter()
+            .map(|state| state.infer.policy_entropy())
+            .sum::<f32>();
+        total / self.len().max(1) as f32
+    }
+    /// Records the outcome and marks the game as ready for training
+    pub fn end(&mut self, outcome: bool, rewards: RewardScheme) {
+        self.ended = Some(now());
+        self.rewards = Some(rewards);
+        self.outcome = Some(outcome);
+        self.lifecycle = Lifecycle::Unprocessed;
+    }
 }
 
 impl Iterator for Sequence {
@@ -174,7 +357,7 @@ pub async fn init_db() -> Result<Rexie> {
     let store = transaction.store(MODEL_STORE)?;
     let model_data = store.get_all(None, None).await?;
     if model_data.is_empty() {
-        let model = Model::new();
+        let model = Model::new(config::current(), &mut rng::from_entropy());
         match model.to_jsobject() {
             Ok(o) => {
                 web_sys::console::log_1(&"Initializing DB with new model".into());
@@ -215,41 +398,173 @@ pub async fn get_current_game() -> Result<Sequence> {
     )
 }
 
-/// Utility function to read a model from browser storage
-pub async fn read_model() -> Result<Model> {
+/// Utility function to read a single game from browser storage
+pub async fn read_sequence(id: f64) -> Result<Option<Sequence>> {
+    let rexie = init_db().await?;
+    let transaction = rexie.transaction(&[STATE_STORE], TransactionMode::ReadOnly)?;
+    let store = transaction.store(STATE_STORE)?;
+    let state_js = store.get(JsValue::from_f64(id)).await?;
+    transaction.done().await?;
+    Ok(
+        state_js.and_then(|s| match serde_wasm_bindgen::from_value::<Sequence>(s) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                web_sys::console::log_1(&e.into());
+                None
+            }
+        }),
+    )
+}
+
+/// Why a stored agent couldn't be read
+#[derive(Debug)]
+pub enum StoreError {
+    Db(Error),
+    /// The stored record doesn't decode or doesn't fit the current config. It's
+    /// left in the store rather than replaced with a new agent.
+    Refused(ModelError),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Db(e) => write!(f, "storage failed: {:?}", e),
+            StoreError::Refused(e) => write!(f, "refusing stored agent: {}", e),
+        }
+    }
+}
+
+impl From<Error> for StoreError {
+    fn from(e: Error) -> Self {
+        StoreError::Db(e)
+    }
+}
+
+impl From<ModelError> for StoreError {
+    fn from(e: ModelError) -> Self {
+        StoreError::Refused(e)
+    }
+}
+
+/// Reads the stored model, or makes a new one if none is stored yet. A stored
+/// model that doesn't fit the current config is an error, not replaced.
+pub async fn read_model() -> std::result::Result<Model, StoreError> {
     let rexie = init_db().await?;
     let transaction = rexie.transaction(&[MODEL_STORE], TransactionMode::ReadOnly)?;
     let store = transaction.store(MODEL_STORE)?;
     let key = Some(JsValue::from_f64(MODEL_DB_KEY_VERSION));
 
     let model_js = store.get(key.into()).await?;
-    match Model::from_jsobject(model_js.into()) {
-        Ok(m) => {
-            transaction.done().await?;
-            return Ok(m);
-        }
-        Err(e) => {
-            web_sys::console::log_1(&e.into());
+    transaction.done().await?;
+    let current = config::current();
+    let model_js = match model_js {
+        Some(model_js) => model_js,
+        None => return Ok(Model::new(current, &mut rng::from_entropy())),
+    };
+    let mut model = Model::from_jsobject(model_js)?;
+    model
+        .config()
+        .check(&current)
+        .map_err(ModelError::Incompatible)?;
+    model.set_train_config(current.train);
+    Ok(model)
+}
+
+/// Reads the stored DQN agent, or makes a new one if none is stored yet. Like
+/// `read_model`, a stored agent that doesn't fit the current config is an error.
+pub async fn read_dqn() -> std::result::Result<Dqn, StoreError> {
+    let rexie = init_db().await?;
+    let transaction = rexie.transaction(&[MODEL_STORE], TransactionMode::ReadOnly)?;
+    let store = transaction.store(MODEL_STORE)?;
+    let key = Some(JsValue::from_f64(DQN_DB_KEY));
+
+    let dqn_js = store.get(key.into()).await?;
+    transaction.done().await?;
+    let current = config::current();
+    let dqn_js = match dqn_js {
+        Some(dqn_js) => dqn_js,
+        None => return Ok(Dqn::new(current, &mut rng::from_entropy())),
+    };
+    let mut dqn = Dqn::from_jsobject(dqn_js)?;
+    dqn.config()
+        .check(&current)
+        .map_err(ModelError::Incompatible)?;
+    dqn.set_train_config(current.train);
+    Ok(dqn)
+}
+
+/// The DQN replay buffer, kept in the model store beside the agent
+#[derive(Deserialize, Serialize)]
+struct ReplayBufferRecord {
+    id: u8,
+    buffer: ReplayBuffer,
+}
+
+/// Reads the stored replay buffer, or an empty one if there isn't one
+pub async fn read_replay_buffer() -> Result<ReplayBuffer> {
+    let rexie = init_db().await?;
+    let transaction = rexie.transaction(&[MODEL_STORE], TransactionMode::ReadOnly)?;
+    let store = transaction.store(MODEL_STORE)?;
+    let key = Some(JsValue::from_f64(REPLAY_DB_KEY));
+
+    let record_js = store.get(key.into()).await?;
+    transaction.done().await?;
+    Ok(
+        serde_wasm_bindgen::from_value::<ReplayBufferRecord>(record_js.into())
+            .map(|record| record.buffer)
+            .unwrap_or_default(),
+    )
+}
+
+/// Reads the kind of agent the current config plays with, and with
+/// `training` set anything it needs only to train, like the DQN replay buffer
+pub async fn read_agent(training: bool) -> std::result::Result<Box<dyn Agent>, StoreError> {
+    Ok(match config::current().agent {
+        AgentKind::PolicyGradient => Box::new(read_model().await?),
+        AgentKind::Dqn => {
+            let mut dqn = read_dqn().await?;
+            if training {
+                dqn.set_buffer(read_replay_buffer().await?);
+            }
+            Box::new(dqn)
         }
-    }
-    Ok(Model::new())
+    })
 }
 
 /// Utility function to write a model to the browser storage
 pub async fn write_model(model: Model) -> Result<()> {
+    write_agent(&model).await
+}
+
+/// Stores an agent under its id, replacing the last one of its kind, and its
+/// replay buffer if it has one
+pub async fn write_agent(agent: &dyn Agent) -> Result<()> {
     let rexie = init_db().await?;
     let transaction = rexie.transaction(&[MODEL_STORE], TransactionMode::ReadWrite)?;
     let store = transaction.store(MODEL_STORE)?;
-    match model.to_jsobject() {
+    match agent.to_jsobject() {
         Ok(o) => {
-            web_sys::console::log_1(&"Initializing DB with new model".into());
-            web_sys::console::log_1(&o);
+            web_sys::console::log_1(&format!("Storing agent {}", agent.id()).into());
             store.put(&o.into(), None).await?;
         }
         Err(e) => {
             web_sys::console::log_1(&e.into());
         }
     };
+    if let Some(buffer) = agent.replay_buffer() {
+        let record = ReplayBufferRecord {
+            id: REPLAY_DB_KEY as u8,
+            buffer: buffer.clone(),
+        };
+        match serde_wasm_bindgen::to_value(&record) {
+            Ok(o) => {
+                store.put(&o, None).await?;
+            }
+            Err(e) => {
+                web_sys::console::log_1(&e.into());
+            }
+        }
+    }
     transaction.done().await?;
     Ok(())
 }
@@ -259,23 +574,44 @@ pub async fn read_unprocessed_states() -> Result<Vec<Sequence>> {
     let rexie = init_db().await?;
     let transaction = rexie.transaction(&[STATE_STORE], TransactionMode::ReadOnly)?;
     let store = transaction.store(STATE_STORE)?;
-    let keyrange = KeyRange::only(&Lifecycle::Unprocessed.into())?;
-    let states_js = store.get_all(Some(keyrange), None).await?;
+    let index = store.index(STATE_STORE)?;
+    let keyrange = KeyRange::only(&JsValue::from(Lifecycle::Unprocessed.to_string()))?;
+    let states_js = index.get_all(Some(keyrange), None).await?;
     transaction.done().await?;
     Ok(states_js
         .iter()
-        .map(
+        .filter_map(
             |state_js| match serde_wasm_bindgen::from_value::<Sequence>(state_js.into()) {
-                Ok(s) => s,
+                Ok(s) => Some(s),
                 Err(e) => {
                     web_sys::console::log_1(&e.into());
-                    Sequence::new()
+                    None
                 }
             },
         )
         .collect::<Vec<_>>())
 }
 
+/// Marks games as trained on so they aren't read as unprocessed again
+pub async fn mark_processed(states: Vec<Sequence>) -> Result<()> {
+    let rexie = init_db().await?;
+    let transaction = rexie.transaction(&[STATE_STORE], TransactionMode::ReadWrite)?;
+    let store = transaction.store(STATE_STORE)?;
+    for mut state in states {
+        state.lifecycle = Lifecycle::Processed;
+        match serde_wasm_bindgen::to_value(&state) {
+            Ok(o) => {
+                store.put(&o, None).await?;
+            }
+            Err(e) => {
+                web_sys::console::log_1(&e.into());
+            }
+        };
+    }
+    transaction.done().await?;
+    Ok(())
+}
+
 /// Utility function to write an update to the browser storage
 pub async fn write_new_state(state: Sequence) -> Result<()> {
     let rexie = init_db().await?;
@@ -296,8 +632,13 @@ pub async fn write_new_state(state: Sequence) -> Result<()> {
 /// Adds a frame to the current game in browser storage
 pub async fn add_frame(frame: State) -> Result<()> {
     let mut state = get_current_game().await?;
+    state.push(frame);
+    write_current_game(state).await
+}
+
+/// Overwrites the current game in browser storage
+pub async fn write_current_game(state: Sequence) -> Result<()> {
     let rexie = init_db().await?;
-    state.sequence.push(frame);
     let transaction = rexie.transaction(&[STATE_STORE], TransactionMode::ReadWrite)?;
     let store = transaction.store(STATE_STORE)?;
     match serde_wasm_bindgen::to_value(&state) {
@@ -316,8 +657,15 @@ pub async fn add_frame(frame: State) -> Result<()> {
 pub async fn end_game(outcome: bool) -> Result<()> {
     let mut state = get_current_game().await?;
     let rexie = init_db().await?;
-    state.outcome = Some(outcome);
-    state.lifecycle = Lifecycle::Unprocessed;
+    state.end(outcome, config::current().train.rewards);
+    web_sys::console::log_1(
+        &format!(
+            "Game {} ended, mean policy entropy {:.3}",
+            state.id,
+            state.mean_entropy()
+        )
+        .into(),
+    );
     let transaction = rexie.transaction(&[STATE_STORE], TransactionMode::ReadWrite)?;
     let store = transaction.store(STATE_STORE)?;
     let id = state.id;
@@ -1,10 +1,19 @@
 importScripts("./pkg/pong_wasm.js");
 
 console.log("Initializing worker");
-const { Model, handle_img, startup, handle_end } = wasm_bindgen;
+const {
+  Model,
+  handle_img,
+  startup,
+  handle_end,
+  set_config,
+  set_batch_size,
+  set_agent,
+  benchmark_inference,
+} = wasm_bindgen;
 
 const DEBUG = false;
-const RESOLUTION = 10;
+let RESOLUTION = 10;
 let mode = "train";
 async function initialize() {
   await wasm_bindgen("./pkg/pong_wasm_bg.wasm");
@@ -32,23 +41,25 @@ function decrease_resolution(state, factor) {
   return new_state;
 }
 
-async function send_state(state) {
+// positions are from player one's side for both players, handle_img mirrors
+// them for player two
+async function send_state(state, positions) {
   if (mode == "human") {
     return;
   }
   if (mode == "play") {
     let data = decrease_resolution(state, RESOLUTION);
-    let choice = await handle_img(data.flat(), true);
+    let choice = await handle_img(data.flat(), positions, 0, true);
     self.postMessage({ type: "movePlayer1", data: choice });
   }
   if (mode == "train") {
     let data = decrease_resolution(state, RESOLUTION);
-    let choice = await handle_img(data.flat(), true);
+    let choice = await handle_img(data.flat(), positions, 0, true);
     self.postMessage({ type: "movePlayer1", data: choice });
     let data2 = decrease_resolution(state, RESOLUTION).map((row) =>
       row.slice().reverse(),
     );
-    let choice2 = await handle_img(data2.flat(), false);
+    let choice2 = await handle_img(data2.flat(), positions, 1, false);
     self.postMessage({ type: "movePlayer2", data: choice2 });
   }
 }
@@ -70,6 +81,7 @@ function display_state(state) {
     }
     str += "\n";
   }
+  console.log(str);
 }
 
 self.onmessage = async (e) => {
@@ -82,7 +94,7 @@ self.onmessage = async (e) => {
       if (DEBUG) {
         display_state(e.data.data);
       }
-      await send_state(e.data.data);
+      await send_state(e.data.data, e.data.positions);
       break;
     case "end":
       await send_end(e.data.data);
@@ -90,9 +102,29 @@ self.onmessage = async (e) => {
     case "mode":
       mode = e.data.data;
       break;
+    case "config":
+      await ready;
+      set_config(e.data.data);
+      RESOLUTION = e.data.data.model.resolution;
+      break;
+    case "batchSize":
+      await ready;
+      set_batch_size(e.data.data);
+      break;
+    case "agent":
+      await ready;
+      set_agent(e.data.data);
+      break;
+    case "benchmark":
+      await ready;
+      self.postMessage({
+        type: "benchmark",
+        data: benchmark_inference(e.data.data),
+      });
+      break;
     default:
       break;
   }
 };
 
-initialize();
+const ready = initialize();